@@ -28,14 +28,14 @@ static COMPRESSED: &[&str] = &[
 static TEMP: &[&str] = &["tmp", "swp", "swo", "swn", "bak", "bk"];
 
 static STYLES: &[(&[&str], &str)] = &[
-    (TEMP, "Style::Fixed(244)"),
-    (IMAGE, "Style::Fixed(133)"),
-    (VIDEO, "Style::Fixed(135)"),
-    (MUSIC, "Style::Fixed(92)"),
-    (LOSSLESS, "Style::Fixed(93)"),
-    (CRYPTO, "Style::Fixed(109)"),
-    (DOCUMENT, "Style::Fixed(105)"),
-    (COMPRESSED, "Style::Red"),
+    (TEMP, "Style::fixed(244)"),
+    (IMAGE, "Style::fixed(133)"),
+    (VIDEO, "Style::fixed(135)"),
+    (MUSIC, "Style::fixed(92)"),
+    (LOSSLESS, "Style::fixed(93)"),
+    (CRYPTO, "Style::fixed(109)"),
+    (DOCUMENT, "Style::fixed(105)"),
+    (COMPRESSED, "Style::RED"),
 ];
 
 use std::io::Write;
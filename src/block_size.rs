@@ -0,0 +1,227 @@
+use crate::utils::Buffer;
+use alloc::vec::Vec;
+
+// This mirrors how gnulib's human.c parses and prints block sizes for GNU ls
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct BlockSize {
+    pub bytes: u64,
+    // Whether amounts are suffixed with the unit, as in 12K for --block-size=K
+    show_unit: bool,
+    binary_unit: bool,
+    // Whether the unit is written out as KB or KiB instead of just K
+    unit_has_b: bool,
+    pub group_digits: bool,
+    // What digits are grouped with, from the LC_NUMERIC locale
+    pub thousands_separator: &'static [u8],
+    // For -h and --si, which pick the largest unit that keeps amounts at least 1
+    autoscale: bool,
+}
+
+pub enum BlockSizeError {
+    Invalid,
+    InvalidSuffix,
+    TooLarge,
+}
+
+impl BlockSize {
+    pub const fn bytes(bytes: u64) -> Self {
+        Self {
+            bytes,
+            show_unit: false,
+            binary_unit: true,
+            unit_has_b: false,
+            group_digits: false,
+            thousands_separator: b"",
+            autoscale: false,
+        }
+    }
+
+    pub const fn human_readable(si: bool) -> Self {
+        Self {
+            bytes: 1,
+            show_unit: true,
+            binary_unit: !si,
+            unit_has_b: false,
+            group_digits: false,
+            thousands_separator: b"",
+            autoscale: true,
+        }
+    }
+
+    pub fn parse(spec: &[u8]) -> Result<Self, BlockSizeError> {
+        match spec {
+            b"human-readable" => return Ok(Self::human_readable(false)),
+            b"si" => return Ok(Self::human_readable(true)),
+            _ => {}
+        }
+
+        let (group_digits, spec) = match spec.strip_prefix(b"'") {
+            Some(rest) => (true, rest),
+            None => (false, spec),
+        };
+
+        let digits = spec.iter().take_while(|b| b.is_ascii_digit()).count();
+        let (number, suffix) = spec.split_at(digits);
+        if number.is_empty() && suffix.is_empty() {
+            return Err(BlockSizeError::Invalid);
+        }
+
+        let mut bytes: u64 = if number.is_empty() { 1 } else { 0 };
+        for digit in number {
+            bytes = bytes
+                .checked_mul(10)
+                .and_then(|b| b.checked_add(u64::from(digit - b'0')))
+                .ok_or(BlockSizeError::TooLarge)?;
+        }
+
+        let (exponent, binary_unit, unit_has_b) = match suffix {
+            [] => (0, true, false),
+            [letter, rest @ ..] => {
+                let exponent = match letter.to_ascii_uppercase() {
+                    b'K' => 1,
+                    b'M' => 2,
+                    b'G' => 3,
+                    b'T' => 4,
+                    b'P' => 5,
+                    b'E' => 6,
+                    b'Z' => 7,
+                    b'Y' => 8,
+                    _ => return Err(BlockSizeError::InvalidSuffix),
+                };
+                match rest {
+                    [] => (exponent, true, false),
+                    b"B" => (exponent, false, true),
+                    b"iB" => (exponent, true, true),
+                    _ => return Err(BlockSizeError::InvalidSuffix),
+                }
+            }
+        };
+
+        let base: u64 = if binary_unit { 1024 } else { 1000 };
+        for _ in 0..exponent {
+            bytes = bytes.checked_mul(base).ok_or(BlockSizeError::TooLarge)?;
+        }
+        if bytes == 0 {
+            return Err(BlockSizeError::Invalid);
+        }
+
+        Ok(Self {
+            bytes,
+            // A size without a number in front of it, like K or MB, is written next to amounts
+            show_unit: number.is_empty(),
+            binary_unit,
+            unit_has_b,
+            group_digits,
+            thousands_separator: b"",
+            autoscale: false,
+        })
+    }
+
+    // Writes amount, given in units of amount_block_size, in units of this block size. Like GNU,
+    // this rounds up.
+    pub fn format(&self, amount: u64, amount_block_size: u64, out: &mut Vec<u8>) {
+        out.clear();
+
+        let total = u128::from(amount) * u128::from(amount_block_size);
+        if self.autoscale {
+            return self.format_autoscaled(total, out);
+        }
+        let scaled = total.div_ceil(u128::from(self.bytes));
+
+        let mut buf = Buffer::new();
+        let digits = buf.format(scaled as u64);
+        if self.group_digits {
+            for (i, digit) in digits.iter().enumerate() {
+                if i != 0 && (digits.len() - i).is_multiple_of(3) {
+                    out.extend_from_slice(self.thousands_separator);
+                }
+                out.push(*digit);
+            }
+        } else {
+            out.extend_from_slice(digits);
+        }
+
+        if self.show_unit {
+            let base: u128 = if self.binary_unit { 1024 } else { 1000 };
+            let mut exponent = 0;
+            let mut power = 1;
+            while power < u128::from(self.bytes) && exponent < 8 {
+                power *= base;
+                exponent += 1;
+            }
+            self.push_unit(exponent, out);
+        }
+    }
+
+    // This is human_readable from gnulib with human_autoscale and human_ceiling: amounts below 10
+    // get one decimal place, and the value shown is rounded up
+    fn format_autoscaled(&self, mut amount: u128, out: &mut Vec<u8>) {
+        let base: u128 = if self.binary_unit { 1024 } else { 1000 };
+        let mut exponent = 0;
+        let mut tenths = 0;
+        // What was dropped below the tenths: 0 for nothing, 1 for less than half a tenth, 2 for
+        // exactly half and 3 for more
+        let mut rounding = 0;
+
+        while amount >= base && exponent < 8 {
+            let r10 = amount % base * 10 + tenths;
+            let r2 = r10 % base * 2 + (rounding >> 1);
+            amount /= base;
+            tenths = r10 / base;
+            rounding = if r2 < base {
+                (r2 + rounding != 0) as u128
+            } else {
+                2 + (base < r2 + rounding) as u128
+            };
+            exponent += 1;
+        }
+
+        let mut show_tenths = false;
+        if exponent > 0 && amount < 10 {
+            if rounding > 0 {
+                tenths += 1;
+                rounding = 0;
+                if tenths == 10 {
+                    amount += 1;
+                    tenths = 0;
+                }
+            }
+            if amount < 10 {
+                show_tenths = true;
+            }
+        }
+
+        if !show_tenths && tenths + rounding > 0 {
+            amount += 1;
+            // 1023.5K becomes 1.0M rather than 1024K
+            if amount == base && exponent < 8 {
+                amount = 1;
+                exponent += 1;
+                show_tenths = true;
+                tenths = 0;
+            }
+        }
+
+        out.extend_from_slice(Buffer::new().format(amount as u64));
+        if show_tenths {
+            out.push(b'.');
+            out.push(b'0' + tenths as u8);
+        }
+        self.push_unit(exponent, out);
+    }
+
+    fn push_unit(&self, exponent: usize, out: &mut Vec<u8>) {
+        if exponent == 1 && !self.binary_unit {
+            out.push(b'k');
+        } else if exponent > 0 {
+            out.push(b"KMGTPEZY"[exponent - 1]);
+        }
+        if self.unit_has_b {
+            if self.binary_unit && exponent > 0 {
+                out.push(b'i');
+            }
+            out.push(b'B');
+        }
+    }
+}
@@ -1,4 +1,9 @@
-use crate::output::OutputBuffer;
+use crate::{
+    block_size::{BlockSize, BlockSizeError},
+    locale::TimeLocale,
+    output::OutputBuffer,
+    style::LsColors,
+};
 use alloc::vec::Vec;
 use veneer::{syscalls::*, CStr, Error};
 
@@ -14,29 +19,46 @@ macro_rules! error {
 
 pub struct App {
     pub print_inode: bool,
-    pub block_size_is_kilobytes: bool,
+    pub print_context: bool,
+    pub block_size: BlockSize,
+    pub file_block_size: BlockSize,
     pub replace_unprintable_bytes: bool,
+    pub quoting_style: QuotingStyle,
     pub reverse_sorting: bool,
     pub grid_sort_direction: SortDirection,
     pub display_size_in_blocks: bool,
     pub display_mode: DisplayMode,
     pub show_all: ShowAll,
+    pub ignore_patterns: Vec<&'static [u8]>,
+    pub hide_patterns: Vec<&'static [u8]>,
     pub suffixes: Suffixes,
     pub follow_symlinks: FollowSymlinks,
     pub recurse: bool,
     pub sort_field: Option<SortField>,
+    pub group_directories_first: bool,
     pub time_field: TimeField,
+    pub show_all_times: bool,
+    pub relative_time: bool,
+    pub show_xattr_indicator: bool,
+    pub time_locale: TimeLocale,
+    pub old_time_format: &'static [u8],
+    pub recent_time_format: &'static [u8],
     pub list_directory_contents: bool,
     pub out: OutputBuffer,
     pub convert_id_to_name: bool,
     pub print_owner: bool,
     pub print_group: bool,
+    pub print_author: bool,
+    // What ends each line of entries, which --zero makes a NUL
+    pub end_of_line: u8,
+    // With -T, grid columns are padded with tabs as well as spaces
+    pub tab_size: usize,
     pub color: Color,
-    pub human_readable_sizes: bool,
-    pub use_si_size_units: bool,
+    pub ls_colors: LsColors,
 
     pub args: Vec<CStr<'static>>,
 
+    environ: &'static [u8],
     etc_passwd: &'static [u8],
     uid_names: Vec<(u32, (usize, usize))>,
     etc_group: &'static [u8],
@@ -45,6 +67,7 @@ pub struct App {
     tzinfo: Option<crate::time::Tzinfo>,
 
     pub needs_details: bool,
+    pub statx_mask: u32,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -59,6 +82,7 @@ pub enum TimeField {
     Accessed,
     Modified,
     StatusChanged,
+    Birth,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -66,6 +90,9 @@ pub enum SortField {
     Name,
     Size,
     Time,
+    Version,
+    Extension,
+    Width,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -79,6 +106,8 @@ pub enum FollowSymlinks {
 pub enum Suffixes {
     None,
     Directories,
+    // Everything but executables, like --indicator-style=file-type
+    FileType,
     All,
 }
 
@@ -95,6 +124,17 @@ pub enum SortDirection {
     Vertical,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum QuotingStyle {
+    Literal,
+    Shell,
+    ShellAlways,
+    ShellEscape,
+    ShellEscapeAlways,
+    C,
+    Escape,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum DisplayMode {
     Grid(usize),
@@ -103,189 +143,869 @@ pub enum DisplayMode {
     Stream,
 }
 
+impl QuotingStyle {
+    fn from_name(name: &[u8]) -> Option<Self> {
+        use QuotingStyle::*;
+        Some(match name {
+            b"literal" => Literal,
+            b"shell" => Shell,
+            b"shell-always" => ShellAlways,
+            b"shell-escape" => ShellEscape,
+            b"shell-escape-always" => ShellEscapeAlways,
+            b"c" => C,
+            b"escape" => Escape,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Argument {
+    None,
+    // The name of the argument, as shown in --help
+    Required(&'static str),
+    // Only the long form can be given an argument, and only with --option=value
+    Optional(&'static str),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Category {
+    Filtering,
+    Sorting,
+    Format,
+    Color,
+    Other,
+}
+
+impl Category {
+    const ALL: [Category; 5] = [
+        Category::Filtering,
+        Category::Sorting,
+        Category::Format,
+        Category::Color,
+        Category::Other,
+    ];
+
+    fn heading(self) -> &'static str {
+        match self {
+            Category::Filtering => "Filtering",
+            Category::Sorting => "Sorting",
+            Category::Format => "Format",
+            Category::Color => "Color",
+            Category::Other => "Other",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Opt {
+    All,
+    AlmostAll,
+    AllTimes,
+    Author,
+    BlockSize,
+    Classify,
+    Color,
+    Columns,
+    Context,
+    Commas,
+    CtimeSort,
+    DereferenceCommandLine,
+    Dereference,
+    Directory,
+    Dired,
+    Escape,
+    FileType,
+    Format,
+    FullTime,
+    GroupDirectoriesFirst,
+    Help,
+    Hide,
+    HideControlChars,
+    HumanReadable,
+    Hyperlink,
+    Ignore,
+    IgnoreBackups,
+    IndicatorStyle,
+    Inode,
+    Kibibytes,
+    Literal,
+    Long,
+    LongWithoutGroup,
+    LongWithoutOwner,
+    NoGroup,
+    NoSort,
+    NumericUidGid,
+    OnePerLine,
+    QuoteName,
+    QuotingStyle,
+    Recursive,
+    RelativeTime,
+    Reverse,
+    Rows,
+    ShowControlChars,
+    Si,
+    Size,
+    SizeSort,
+    Slash,
+    Sort,
+    TabSize,
+    AtimeSort,
+    TimeSort,
+    Time,
+    TimeStyle,
+    Unsorted,
+    Version,
+    VersionSort,
+    Width,
+    ExtensionSort,
+    XattrIndicator,
+    Zero,
+}
+
+struct CliOption {
+    short: Option<u8>,
+    long: Option<&'static str>,
+    argument: Argument,
+    opt: Opt,
+    category: Category,
+    help: &'static str,
+}
+
+const fn option(
+    short: u8,
+    long: &'static str,
+    opt: Opt,
+    category: Category,
+    help: &'static str,
+) -> CliOption {
+    CliOption {
+        short: Some(short),
+        long: Some(long),
+        argument: Argument::None,
+        opt,
+        category,
+        help,
+    }
+}
+
+const fn short_option(short: u8, opt: Opt, category: Category, help: &'static str) -> CliOption {
+    CliOption {
+        short: Some(short),
+        long: None,
+        argument: Argument::None,
+        opt,
+        category,
+        help,
+    }
+}
+
+const fn long_option(
+    long: &'static str,
+    argument: Argument,
+    opt: Opt,
+    category: Category,
+    help: &'static str,
+) -> CliOption {
+    CliOption {
+        short: None,
+        long: Some(long),
+        argument,
+        opt,
+        category,
+        help,
+    }
+}
+
+// Every option we understand, with the same short and long names as GNU ls. This is also where
+// --help gets its text from.
+static OPTIONS: &[CliOption] = {
+    use Category::*;
+    &[
+        option(
+            b'a',
+            "all",
+            Opt::All,
+            Filtering,
+            "do not ignore entries starting with .",
+        ),
+        option(
+            b'A',
+            "almost-all",
+            Opt::AlmostAll,
+            Filtering,
+            "do not list implied . and ..",
+        ),
+        long_option(
+            "all-times",
+            Argument::None,
+            Opt::AllTimes,
+            Format,
+            "with -l, show modification, change and access times in separate columns",
+        ),
+        long_option(
+            "author",
+            Argument::None,
+            Opt::Author,
+            Format,
+            "with -l, print the author of each file",
+        ),
+        option(
+            b'b',
+            "escape",
+            Opt::Escape,
+            Format,
+            "print C-style escapes for nongraphic characters",
+        ),
+        option(
+            b'B',
+            "ignore-backups",
+            Opt::IgnoreBackups,
+            Filtering,
+            "do not list entries ending with ~",
+        ),
+        long_option(
+            "block-size",
+            Argument::Required("SIZE"),
+            Opt::BlockSize,
+            Format,
+            "scale sizes by SIZE when printing them",
+        ),
+        short_option(
+            b'c',
+            Opt::CtimeSort,
+            Sorting,
+            "sort by, and show, status change time",
+        ),
+        short_option(b'C', Opt::Columns, Format, "list entries by columns"),
+        long_option(
+            "color",
+            Argument::Optional("WHEN"),
+            Opt::Color,
+            Color,
+            "color the output WHEN: always (default), auto or never",
+        ),
+        option(
+            b'd',
+            "directory",
+            Opt::Directory,
+            Filtering,
+            "list directories themselves, not their contents",
+        ),
+        option(
+            b'D',
+            "dired",
+            Opt::Dired,
+            Format,
+            "generate output designed for Emacs' dired mode; not supported",
+        ),
+        short_option(b'f', Opt::NoSort, Sorting, "do not sort, enable -a"),
+        CliOption {
+            short: Some(b'F'),
+            long: Some("classify"),
+            argument: Argument::Optional("WHEN"),
+            opt: Opt::Classify,
+            category: Format,
+            help: "append indicator (one of */=>@|) to entries WHEN",
+        },
+        long_option(
+            "file-type",
+            Argument::None,
+            Opt::FileType,
+            Format,
+            "likewise, except do not append '*'",
+        ),
+        long_option(
+            "format",
+            Argument::Required("WORD"),
+            Opt::Format,
+            Format,
+            "across, commas, horizontal, long, single-column, verbose or vertical",
+        ),
+        long_option(
+            "full-time",
+            Argument::None,
+            Opt::FullTime,
+            Format,
+            "like -l --time-style=full-iso",
+        ),
+        short_option(
+            b'g',
+            Opt::LongWithoutOwner,
+            Format,
+            "like -l, but do not list owner",
+        ),
+        option(
+            b'G',
+            "no-group",
+            Opt::NoGroup,
+            Format,
+            "in a long listing, don't print group names",
+        ),
+        option(
+            b'h',
+            "human-readable",
+            Opt::HumanReadable,
+            Format,
+            "print sizes like 1K 234M 2G etc.",
+        ),
+        long_option(
+            "si",
+            Argument::None,
+            Opt::Si,
+            Format,
+            "likewise, but use powers of 1000 not 1024",
+        ),
+        option(
+            b'H',
+            "dereference-command-line",
+            Opt::DereferenceCommandLine,
+            Filtering,
+            "follow symbolic links listed on the command line",
+        ),
+        long_option(
+            "group-directories-first",
+            Argument::None,
+            Opt::GroupDirectoriesFirst,
+            Sorting,
+            "group directories before files, unless sorting is off",
+        ),
+        long_option(
+            "help",
+            Argument::None,
+            Opt::Help,
+            Other,
+            "display this help and exit",
+        ),
+        long_option(
+            "hyperlink",
+            Argument::Optional("WHEN"),
+            Opt::Hyperlink,
+            Format,
+            "hyperlink file names WHEN; only never is supported",
+        ),
+        long_option(
+            "hide",
+            Argument::Required("PATTERN"),
+            Opt::Hide,
+            Filtering,
+            "do not list entries matching PATTERN, unless -a or -A is given",
+        ),
+        CliOption {
+            short: Some(b'I'),
+            long: Some("ignore"),
+            argument: Argument::Required("PATTERN"),
+            opt: Opt::Ignore,
+            category: Filtering,
+            help: "do not list entries matching shell PATTERN",
+        },
+        long_option(
+            "indicator-style",
+            Argument::Required("WORD"),
+            Opt::IndicatorStyle,
+            Format,
+            "append indicators in style WORD: none, slash (-p), file-type or classify (-F)",
+        ),
+        option(
+            b'i',
+            "inode",
+            Opt::Inode,
+            Format,
+            "print the index number of each file",
+        ),
+        option(
+            b'k',
+            "kibibytes",
+            Opt::Kibibytes,
+            Format,
+            "default to 1024-byte blocks for -s and totals",
+        ),
+        short_option(b'l', Opt::Long, Format, "use a long listing format"),
+        option(
+            b'L',
+            "dereference",
+            Opt::Dereference,
+            Filtering,
+            "show the files symbolic links point to",
+        ),
+        short_option(
+            b'm',
+            Opt::Commas,
+            Format,
+            "fill width with a comma separated list of entries",
+        ),
+        option(
+            b'n',
+            "numeric-uid-gid",
+            Opt::NumericUidGid,
+            Format,
+            "like -l, but list numeric user and group IDs",
+        ),
+        option(
+            b'N',
+            "literal",
+            Opt::Literal,
+            Format,
+            "print entry names without quoting",
+        ),
+        short_option(
+            b'o',
+            Opt::LongWithoutGroup,
+            Format,
+            "like -l, but do not list group information",
+        ),
+        short_option(
+            b'p',
+            Opt::Slash,
+            Format,
+            "append / indicator to directories",
+        ),
+        option(
+            b'q',
+            "hide-control-chars",
+            Opt::HideControlChars,
+            Format,
+            "print ? instead of nongraphic characters",
+        ),
+        long_option(
+            "show-control-chars",
+            Argument::None,
+            Opt::ShowControlChars,
+            Format,
+            "show nongraphic characters as-is",
+        ),
+        option(
+            b'Q',
+            "quote-name",
+            Opt::QuoteName,
+            Format,
+            "enclose entry names in double quotes",
+        ),
+        long_option(
+            "quoting-style",
+            Argument::Required("WORD"),
+            Opt::QuotingStyle,
+            Format,
+            "literal, shell, shell-always, shell-escape, shell-escape-always, c or escape",
+        ),
+        long_option(
+            "relative-time",
+            Argument::None,
+            Opt::RelativeTime,
+            Format,
+            "with -l, show times as ages like 5 min ago or in 2 days",
+        ),
+        option(
+            b'r',
+            "reverse",
+            Opt::Reverse,
+            Sorting,
+            "reverse order while sorting",
+        ),
+        option(
+            b'R',
+            "recursive",
+            Opt::Recursive,
+            Filtering,
+            "list subdirectories recursively",
+        ),
+        option(
+            b's',
+            "size",
+            Opt::Size,
+            Format,
+            "print the allocated size of each file, in blocks",
+        ),
+        short_option(
+            b'S',
+            Opt::SizeSort,
+            Sorting,
+            "sort by file size, largest first",
+        ),
+        long_option(
+            "sort",
+            Argument::Required("WORD"),
+            Opt::Sort,
+            Sorting,
+            "none, size, time, version, extension or width instead of name",
+        ),
+        short_option(
+            b't',
+            Opt::TimeSort,
+            Sorting,
+            "sort by modification time, newest first",
+        ),
+        CliOption {
+            short: Some(b'T'),
+            long: Some("tabsize"),
+            argument: Argument::Required("COLS"),
+            opt: Opt::TabSize,
+            category: Format,
+            help: "pad columns with tabs, with stops every COLS, as well as spaces",
+        },
+        long_option(
+            "time",
+            Argument::Required("WORD"),
+            Opt::Time,
+            Format,
+            "show WORD instead of modification time: atime, access, use, ctime, status, birth or creation",
+        ),
+        long_option(
+            "time-style",
+            Argument::Required("TIME_STYLE"),
+            Opt::TimeStyle,
+            Format,
+            "full-iso, long-iso, iso, locale or +FORMAT; see strftime",
+        ),
+        short_option(
+            b'u',
+            Opt::AtimeSort,
+            Sorting,
+            "sort by, and show, access time",
+        ),
+        short_option(
+            b'U',
+            Opt::Unsorted,
+            Sorting,
+            "do not sort; list entries in directory order",
+        ),
+        short_option(
+            b'v',
+            Opt::VersionSort,
+            Sorting,
+            "natural sort of (version) numbers within text",
+        ),
+        CliOption {
+            short: Some(b'w'),
+            long: Some("width"),
+            argument: Argument::Required("COLS"),
+            opt: Opt::Width,
+            category: Format,
+            help: "set output width to COLS, 0 means no limit",
+        },
+        short_option(
+            b'x',
+            Opt::Rows,
+            Format,
+            "list entries by lines instead of by columns",
+        ),
+        short_option(
+            b'X',
+            Opt::ExtensionSort,
+            Sorting,
+            "sort alphabetically by entry extension",
+        ),
+        option(
+            b'Z',
+            "context",
+            Opt::Context,
+            Format,
+            "print any security context of each file",
+        ),
+        short_option(b'1', Opt::OnePerLine, Format, "list one file per line"),
+        short_option(
+            b'@',
+            Opt::XattrIndicator,
+            Format,
+            "with -l, print @ after the permissions of files with user extended attributes",
+        ),
+        long_option(
+            "zero",
+            Argument::None,
+            Opt::Zero,
+            Format,
+            "end each output line with NUL, not newline",
+        ),
+        long_option(
+            "version",
+            Argument::None,
+            Opt::Version,
+            Other,
+            "output version information and exit",
+        ),
+    ]
+};
+
+// The options column is as wide as the widest option, like "  -a, --all"
+fn help_option_width(option: &CliOption) -> usize {
+    let long = match option.long {
+        Some(long) => 4 + long.len(),
+        None => 0,
+    };
+    let argument = match option.argument {
+        Argument::None => 0,
+        Argument::Required(name) => 1 + name.len(),
+        Argument::Optional(name) => 3 + name.len(),
+    };
+    4 + long + argument
+}
+
+fn print_help(out: &mut OutputBuffer) {
+    let width = OPTIONS.iter().map(help_option_width).max().unwrap_or(0) + 2;
+
+    out.write(b"Usage: fls [OPTION]... [FILE]...\n")
+        .write(b"List information about the FILEs (the current directory by default).\n");
+    for category in Category::ALL {
+        out.write(b"\n")
+            .write(category.heading().as_bytes())
+            .write(b":\n");
+        for option in OPTIONS.iter().filter(|o| o.category == category) {
+            match option.short {
+                Some(short) => out.write(b"  -").push(short),
+                None => out.write(b"    "),
+            };
+            if let Some(long) = option.long {
+                if option.short.is_some() {
+                    out.write(b", ");
+                } else {
+                    out.write(b"  ");
+                }
+                out.write(b"--").write(long.as_bytes());
+            }
+            match option.argument {
+                Argument::None => {}
+                Argument::Required(name) => {
+                    out.write(b"=").write(name.as_bytes());
+                }
+                Argument::Optional(name) => {
+                    out.write(b"[=").write(name.as_bytes()).write(b"]");
+                }
+            }
+            for _ in help_option_width(option)..width {
+                out.push(b' ');
+            }
+            out.write(option.help.as_bytes()).write(b"\n");
+        }
+    }
+    out.flush();
+}
+
+enum LongOptionError {
+    Unrecognized,
+    Ambiguous,
+}
+
+// Like getopt_long, this accepts any unambiguous prefix of a long option's name
+fn find_long_option(name: &[u8]) -> Result<&'static CliOption, LongOptionError> {
+    let mut found = None;
+    for option in OPTIONS {
+        let long = match option.long {
+            Some(long) if long.as_bytes().starts_with(name) => long.as_bytes(),
+            _ => continue,
+        };
+        if long == name {
+            return Ok(option);
+        }
+        match found {
+            None => found = Some(option),
+            Some(other) if other.opt != option.opt => return Err(LongOptionError::Ambiguous),
+            Some(_) => {}
+        }
+    }
+    found.ok_or(LongOptionError::Unrecognized)
+}
+
+// Settings that can't be applied as soon as their option is parsed, because their meaning
+// depends on the environment or on whether stdout is a terminal
+#[derive(Default)]
+struct Deferred {
+    print_help: bool,
+    print_version: bool,
+    quoting_style: Option<QuotingStyle>,
+    hide_control_chars: Option<bool>,
+    block_size: Option<BlockSize>,
+    block_size_is_kilobytes: bool,
+    time_style: Option<&'static [u8]>,
+    line_width: Option<usize>,
+    // -C and -x lay out a grid even when stdout isn't a terminal
+    grid_requested: bool,
+}
+
+fn invalid_argument(value: &[u8], name: &[u8]) -> bool {
+    error!("invalid argument \'", value, "\' for \'--", name, "\'\n");
+    false
+}
+
+fn unsupported_option(name: &[u8]) -> bool {
+    error!("option \'--", name, "\' is not supported\n");
+    false
+}
+
+// A width for -w or -T, which like GNU ls has to be a plain decimal number
+fn parse_columns(value: &[u8]) -> Option<usize> {
+    if value.is_empty() {
+        return None;
+    }
+    value.iter().try_fold(0usize, |n, &b| {
+        if b.is_ascii_digit() {
+            n.checked_mul(10)?.checked_add(usize::from(b - b'0'))
+        } else {
+            None
+        }
+    })
+}
+
+// The strftime formats for times more than six months old, and for recent ones
+fn time_formats(
+    style: &'static [u8],
+    locale: &TimeLocale,
+) -> Option<(&'static [u8], &'static [u8])> {
+    // +FORMAT, or +OLD_FORMAT and RECENT_FORMAT on two lines
+    if let Some(format) = style.strip_prefix(b"+") {
+        return Some(match format.iter().position(|b| *b == b'\n') {
+            Some(i) => (&format[..i], &format[i + 1..]),
+            None => (format, format),
+        });
+    }
+    // posix- styles are only used outside of the POSIX locale
+    let style: &[u8] = match style.strip_prefix(b"posix-") {
+        Some(_) if locale.is_posix() => b"locale",
+        Some(style) => style,
+        None => style,
+    };
+    Some(match style {
+        b"full-iso" => (
+            &b"%Y-%m-%d %H:%M:%S.%N %z"[..],
+            &b"%Y-%m-%d %H:%M:%S.%N %z"[..],
+        ),
+        b"long-iso" => (&b"%Y-%m-%d %H:%M"[..], &b"%Y-%m-%d %H:%M"[..]),
+        b"iso" => (&b"%Y-%m-%d "[..], &b"%m-%d %H:%M"[..]),
+        b"locale" => locale.date_order.formats(),
+        _ => return None,
+    })
+}
+
 impl App {
     #[inline(never)]
     pub fn from_arguments(raw_args: impl Iterator<Item = CStr<'static>>) -> Result<Self, Error> {
-        let mut print_version = false;
-        let mut switches = Vec::with_capacity(64);
+        let mut deferred = Deferred::default();
         let mut args_valid = true;
 
         let mut hit_only_arg_marker = false;
 
         let mut app = App {
             print_inode: false,
-            block_size_is_kilobytes: false,
+            print_context: false,
+            block_size: BlockSize::bytes(1024),
+            file_block_size: BlockSize::bytes(1),
             replace_unprintable_bytes: false,
+            quoting_style: QuotingStyle::Literal,
             reverse_sorting: false,
-            grid_sort_direction: SortDirection::Horizontal,
+            grid_sort_direction: SortDirection::Vertical,
             display_size_in_blocks: false,
             display_mode: DisplayMode::Grid(0),
             show_all: ShowAll::No,
+            ignore_patterns: Vec::new(),
+            hide_patterns: Vec::new(),
             suffixes: Suffixes::None,
             follow_symlinks: FollowSymlinks::Never,
             recurse: false,
             sort_field: Some(SortField::Name),
+            group_directories_first: false,
             time_field: TimeField::Modified,
+            show_all_times: false,
+            relative_time: false,
+            show_xattr_indicator: false,
+            time_locale: TimeLocale::posix(),
+            old_time_format: &[],
+            recent_time_format: &[],
             list_directory_contents: true,
             convert_id_to_name: true,
             print_owner: true,
             print_group: true,
+            print_author: false,
+            end_of_line: b'\n',
+            tab_size: 0,
             color: Color::Auto,
-            human_readable_sizes: false,
-            use_si_size_units: false,
+            ls_colors: LsColors::builtin(),
             out: OutputBuffer::to_fd(1),
             args: Vec::with_capacity(4),
             uid_names: Vec::new(),
             gid_names: Vec::new(),
+            environ: Self::init_environ(),
             etc_passwd: &[],
             etc_group: &[],
             needs_details: false,
+            statx_mask: 0,
             tzinfo: None,
         };
 
-        for arg in raw_args.skip(1) {
-            if arg.as_bytes() == b"--" {
-                hit_only_arg_marker = true;
-            } else if hit_only_arg_marker {
+        let mut raw_args = raw_args.skip(1);
+        while let Some(arg) = raw_args.next() {
+            let bytes = arg.as_bytes();
+            if hit_only_arg_marker || bytes == b"-" || !bytes.starts_with(b"-") {
                 app.args.push(arg);
-            // Things like --color=always
-            } else if arg.as_bytes().starts_with(b"--") {
-                match arg.as_bytes() {
-                    b"--color=never" => app.color = Color::Never,
-                    b"--color=auto" => app.color = Color::Auto,
-                    b"--color=always" => app.color = Color::Always,
-                    b"--version" => print_version = true,
-
-                    b"--si" => {
-                        app.human_readable_sizes = true;
-                        app.use_si_size_units = true;
-                    }
-
-                    _ => {
+            } else if bytes == b"--" {
+                hit_only_arg_marker = true;
+            // Things like --color=always or --block-size K
+            } else if let Some(long) = bytes.strip_prefix(b"--") {
+                let (name, value) = match long.iter().position(|b| *b == b'=') {
+                    Some(i) => (&long[..i], Some(&long[i + 1..])),
+                    None => (long, None),
+                };
+                let option = match find_long_option(name) {
+                    Ok(option) => option,
+                    Err(LongOptionError::Unrecognized) => {
                         error!("unrecognized option \'", arg, "\'\n");
                         args_valid = false;
+                        continue;
                     }
-                }
+                    Err(LongOptionError::Ambiguous) => {
+                        error!("option \'--", name, "\' is ambiguous; possibilities:");
+                        for long in OPTIONS.iter().filter_map(|o| o.long) {
+                            if long.as_bytes().starts_with(name) {
+                                error!(" \'--", long, "\'");
+                            }
+                        }
+                        error!("\n");
+                        args_valid = false;
+                        continue;
+                    }
+                };
+                let long = option.long.unwrap_or_default();
+                let value = match (option.argument, value) {
+                    (Argument::None, Some(_)) => {
+                        error!("option \'--", long, "\' doesn't allow an argument\n");
+                        args_valid = false;
+                        continue;
+                    }
+                    (Argument::Required(_), None) => match raw_args.next() {
+                        Some(value) => Some(value.as_bytes()),
+                        None => {
+                            error!("option \'--", long, "\' requires an argument\n");
+                            args_valid = false;
+                            continue;
+                        }
+                    },
+                    (_, value) => value,
+                };
+                args_valid &= app.apply_option(option.opt, value, &mut deferred);
             // Things like -R
-            } else if arg.get(0) == Some(b'-') {
-                switches.extend(arg.as_bytes().iter().copied().skip(1));
             } else {
-                app.args.push(arg);
-            }
-        }
-
-        #[allow(non_snake_case)]
-        let mut switches_contains_H_or_L = false;
-        for s in &switches {
-            if s == &b'H' || s == &b'L' {
-                switches_contains_H_or_L = true;
-                break;
-            }
-        }
-
-        for switch in switches.iter().copied() {
-            match switch {
-                b'A' => {
-                    app.show_all = ShowAll::Almost;
-                }
-                b'C' => {
-                    app.display_mode = DisplayMode::Grid(0);
-                    app.grid_sort_direction = SortDirection::Vertical;
-                }
-                b'F' => {
-                    if !switches_contains_H_or_L {
-                        app.follow_symlinks = FollowSymlinks::Never;
-                    }
-                    app.suffixes = Suffixes::All;
-                }
-                b'H' => {
-                    app.follow_symlinks = FollowSymlinks::WhenExplicit;
-                }
-                b'L' => {
-                    app.follow_symlinks = FollowSymlinks::Always;
-                }
-                b'R' => {
-                    app.recurse = true;
-                }
-                b'S' => {
-                    app.sort_field = Some(SortField::Size);
-                }
-                b'a' => {
-                    app.show_all = ShowAll::Yes;
-                }
-                b'c' => {
-                    app.time_field = TimeField::StatusChanged;
-                    app.sort_field = Some(SortField::Time);
-                }
-                b'd' => {
-                    if !switches_contains_H_or_L {
-                        app.follow_symlinks = FollowSymlinks::Never;
+                for (i, switch) in bytes.iter().copied().enumerate().skip(1) {
+                    let option = match OPTIONS.iter().find(|o| o.short == Some(switch)) {
+                        Some(option) => option,
+                        None => {
+                            error!("invalid option \'", switch, "\'\n");
+                            args_valid = false;
+                            continue;
+                        }
+                    };
+                    // Like -I PATTERN or -IPATTERN; the rest of this argument is the value
+                    if matches!(option.argument, Argument::Required(_)) {
+                        let value = match &bytes[i + 1..] {
+                            [] => raw_args.next().map(|value| value.as_bytes()),
+                            rest => Some(rest),
+                        };
+                        if value.is_none() {
+                            error!("option requires an argument -- \'", switch, "\'\n");
+                            args_valid = false;
+                        } else {
+                            args_valid &= app.apply_option(option.opt, value, &mut deferred);
+                        }
+                        break;
                     }
-                    app.list_directory_contents = false;
-                }
-                b'f' => {
-                    app.sort_field = None;
-                    app.show_all = ShowAll::Yes;
-                }
-                b'g' => {
-                    app.display_mode = DisplayMode::Long;
-                    app.print_owner = false;
-                }
-                b'h' => {
-                    app.human_readable_sizes = true;
-                }
-                b'i' => {
-                    app.print_inode = true;
-                }
-                b'k' => {
-                    app.block_size_is_kilobytes = true;
-                }
-                b'l' => {
-                    app.display_mode = DisplayMode::Long;
-                }
-                b'm' => {
-                    app.display_mode = DisplayMode::Stream;
-                }
-                b'n' => {
-                    app.display_mode = DisplayMode::Long;
-                    app.convert_id_to_name = false;
-                }
-                b'o' => {
-                    app.display_mode = DisplayMode::Long;
-                    app.print_group = false;
-                }
-                b'p' => {
-                    app.suffixes = Suffixes::Directories;
-                }
-                b'q' => {
-                    app.replace_unprintable_bytes = true;
-                    unimplemented!("-q is not yet implemented");
-                }
-                b'r' => {
-                    app.reverse_sorting = true;
-                }
-                b's' => {
-                    app.display_size_in_blocks = true;
-                }
-                b't' => {
-                    app.time_field = TimeField::Modified;
-                    app.sort_field = Some(SortField::Time);
-                }
-                b'u' => {
-                    app.time_field = TimeField::Accessed;
-                    app.sort_field = Some(SortField::Time);
-                }
-                b'x' => {
-                    app.grid_sort_direction = SortDirection::Horizontal;
-                    unimplemented!("-x is not yet implemented");
-                }
-                b'1' => match app.display_mode {
-                    DisplayMode::Long => {}
-                    _ => app.display_mode = DisplayMode::SingleColumn,
-                },
-                s => {
-                    error!("invalid option \'", s, "\'\n");
-                    args_valid = false;
+                    args_valid &= app.apply_option(option.opt, None, &mut deferred);
                 }
             }
         }
@@ -294,7 +1014,12 @@ impl App {
             return Err(Error(-1));
         }
 
-        if print_version {
+        if deferred.print_help {
+            print_help(&mut app.out);
+            exit(0);
+        }
+
+        if deferred.print_version {
             app.out
                 .write(b"fls ")
                 .write(env!("CARGO_PKG_VERSION").as_bytes())
@@ -303,19 +1028,91 @@ impl App {
             exit(0);
         }
 
+        // Like GNU ls, the locale and time style only matter for long listings, so a bad
+        // TIME_STYLE is only an error there
+        if app.display_mode == DisplayMode::Long {
+            app.time_locale = TimeLocale::new(app.locale_name(b"LC_TIME"));
+
+            // TIME_STYLE is used when there's no --time-style
+            let time_style = deferred
+                .time_style
+                .or_else(|| app.getenv(b"TIME_STYLE"))
+                .unwrap_or(b"locale");
+            match time_formats(time_style, &app.time_locale) {
+                Some((old, recent)) => {
+                    app.old_time_format = old;
+                    app.recent_time_format = recent;
+                }
+                None => {
+                    invalid_argument(time_style, b"time-style");
+                    return Err(Error(-1));
+                }
+            }
+        }
+
+        // The block size is either set on the command line or comes from the environment, in
+        // which case it also applies to file sizes. -k only overrides the environment.
+        if let Some(size) = deferred.block_size {
+            app.block_size = size;
+            app.file_block_size = size;
+        } else {
+            let spec = app
+                .getenv(b"LS_BLOCK_SIZE")
+                .or_else(|| app.getenv(b"BLOCK_SIZE"));
+            if app.getenv(b"POSIXLY_CORRECT").is_some() {
+                app.block_size = BlockSize::bytes(512);
+            }
+            if let Some(Ok(size)) = spec.map(BlockSize::parse) {
+                app.block_size = size;
+                app.file_block_size = size;
+            }
+            if deferred.block_size_is_kilobytes {
+                app.block_size = BlockSize::bytes(1024);
+            }
+        }
+
+        // Digits grouped by a ' in the block size are separated like the LC_NUMERIC locale says
+        if app.block_size.group_digits || app.file_block_size.group_digits {
+            let separator = crate::locale::thousands_separator(app.locale_name(b"LC_NUMERIC"));
+            app.block_size.thousands_separator = separator;
+            app.file_block_size.thousands_separator = separator;
+        }
+
         let terminal_width = winsize().ok().map(|d| d.ws_col as usize);
 
-        match (terminal_width, app.display_mode) {
-            (Some(width), DisplayMode::Grid(_)) => app.display_mode = DisplayMode::Grid(width),
-            (None, DisplayMode::Grid(_)) => app.display_mode = DisplayMode::SingleColumn,
-            _ => {}
+        // Like GNU ls, grids are 80 columns wide when neither -w nor the terminal say otherwise
+        if let DisplayMode::Grid(_) = app.display_mode {
+            let width = deferred
+                .line_width
+                .or(terminal_width.filter(|&width| width > 0))
+                .unwrap_or(80);
+            app.display_mode = match width {
+                _ if terminal_width.is_none() && !deferred.grid_requested => {
+                    DisplayMode::SingleColumn
+                }
+                0 => DisplayMode::Grid(usize::MAX),
+                width => DisplayMode::Grid(width),
+            };
         }
 
+        app.quoting_style = deferred
+            .quoting_style
+            .unwrap_or(if terminal_width.is_some() {
+                QuotingStyle::ShellEscape
+            } else {
+                QuotingStyle::Literal
+            });
+        app.replace_unprintable_bytes = deferred
+            .hide_control_chars
+            .unwrap_or(terminal_width.is_some());
+
         if terminal_width.is_none() && app.color == Color::Auto {
             app.color = Color::Never;
         }
         if app.color == Color::Never {
             app.out.color = false;
+        } else if let Some(spec) = app.getenv(b"LS_COLORS").filter(|s| !s.is_empty()) {
+            app.ls_colors = LsColors::parse(spec);
         }
 
         if app.display_mode == DisplayMode::Long {
@@ -325,18 +1122,293 @@ impl App {
                 &mut app.uid_names,
             )?;
             Self::init_id_map(&b"/etc/group\0"[..], &mut app.etc_group, &mut app.gid_names)?;
-            app.tzinfo = Some(crate::time::Tzinfo::new());
+            app.tzinfo = Some(crate::time::Tzinfo::new(
+                app.getenv(b"TZ"),
+                app.getenv(b"TZDIR"),
+            ));
         }
 
         app.needs_details = app.display_mode == DisplayMode::Long
             || app.sort_field == Some(SortField::Time)
             || app.sort_field == Some(SortField::Size)
-            || app.display_size_in_blocks;
+            || app.display_size_in_blocks
+            || app.print_context;
+
+        // Only ask the filesystem for what we are going to show or sort by
+        let time_mask = match app.time_field {
+            TimeField::Accessed => libc::STATX_ATIME,
+            TimeField::Modified => libc::STATX_MTIME,
+            TimeField::StatusChanged => libc::STATX_CTIME,
+            TimeField::Birth => libc::STATX_BTIME,
+        };
+        app.statx_mask = libc::STATX_TYPE | libc::STATX_MODE;
+        // Files with several hard links have their own color
+        if app.color != Color::Never {
+            app.statx_mask |= libc::STATX_NLINK;
+        }
+        if app.display_mode == DisplayMode::Long && app.show_all_times {
+            app.statx_mask |= libc::STATX_MTIME | libc::STATX_CTIME | libc::STATX_ATIME;
+        }
+        if app.display_mode == DisplayMode::Long {
+            app.statx_mask |= libc::STATX_NLINK
+                | libc::STATX_UID
+                | libc::STATX_GID
+                | libc::STATX_SIZE
+                | libc::STATX_BLOCKS
+                | time_mask;
+        }
+        match app.sort_field {
+            Some(SortField::Time) => app.statx_mask |= time_mask,
+            Some(SortField::Size) => app.statx_mask |= libc::STATX_SIZE,
+            _ => {}
+        }
+        if app.display_size_in_blocks {
+            app.statx_mask |= libc::STATX_BLOCKS;
+        }
+        if app.print_inode {
+            app.statx_mask |= libc::STATX_INO;
+        }
 
         Ok(app)
     }
 
-    pub fn convert_to_localtime(&self, time: i64) -> crate::time::LocalTime {
+    // Returns false if the option's argument is invalid
+    fn apply_option(
+        &mut self,
+        opt: Opt,
+        value: Option<&'static [u8]>,
+        deferred: &mut Deferred,
+    ) -> bool {
+        match opt {
+            Opt::All => self.show_all = ShowAll::Yes,
+            Opt::AlmostAll => self.show_all = ShowAll::Almost,
+            Opt::AllTimes => self.show_all_times = true,
+            Opt::Author => self.print_author = true,
+            Opt::BlockSize => {
+                let spec = value.unwrap_or_default();
+                match BlockSize::parse(spec) {
+                    Ok(size) => deferred.block_size = Some(size),
+                    Err(err) => {
+                        let (before, after) = match err {
+                            BlockSizeError::Invalid => ("invalid ", ""),
+                            BlockSizeError::InvalidSuffix => ("invalid suffix in ", ""),
+                            BlockSizeError::TooLarge => ("", " too large"),
+                        };
+                        error!(before, "--block-size argument \'", spec, "\'", after, "\n");
+                        return false;
+                    }
+                }
+            }
+            Opt::Classify => match value {
+                None | Some(b"always" | b"yes" | b"force") => self.suffixes = Suffixes::All,
+                Some(b"never" | b"no" | b"none") => self.suffixes = Suffixes::None,
+                Some(b"auto" | b"tty" | b"if-tty") => {
+                    if winsize().is_ok() {
+                        self.suffixes = Suffixes::All;
+                    }
+                }
+                Some(value) => return invalid_argument(value, b"classify"),
+            },
+            Opt::Color => match value {
+                None | Some(b"always" | b"yes" | b"force") => self.color = Color::Always,
+                Some(b"never" | b"no" | b"none") => self.color = Color::Never,
+                Some(b"auto" | b"tty" | b"if-tty") => self.color = Color::Auto,
+                Some(value) => return invalid_argument(value, b"color"),
+            },
+            Opt::Columns => {
+                self.display_mode = DisplayMode::Grid(0);
+                self.grid_sort_direction = SortDirection::Vertical;
+                deferred.grid_requested = true;
+            }
+            Opt::Commas => self.display_mode = DisplayMode::Stream,
+            Opt::Context => self.print_context = true,
+            Opt::CtimeSort => {
+                self.time_field = TimeField::StatusChanged;
+                self.sort_field = Some(SortField::Time);
+            }
+            Opt::DereferenceCommandLine => self.follow_symlinks = FollowSymlinks::WhenExplicit,
+            Opt::Dereference => self.follow_symlinks = FollowSymlinks::Always,
+            Opt::Directory => self.list_directory_contents = false,
+            // Neither has an effect that fls can produce, so they're refused rather than ignored
+            Opt::Dired => return unsupported_option(b"dired"),
+            Opt::Escape => deferred.quoting_style = Some(QuotingStyle::Escape),
+            Opt::FileType => self.suffixes = Suffixes::FileType,
+            Opt::Format => match value.unwrap_or_default() {
+                b"verbose" | b"long" => self.display_mode = DisplayMode::Long,
+                b"commas" => self.display_mode = DisplayMode::Stream,
+                b"horizontal" | b"across" => return self.apply_option(Opt::Rows, None, deferred),
+                b"vertical" => return self.apply_option(Opt::Columns, None, deferred),
+                b"single-column" => self.display_mode = DisplayMode::SingleColumn,
+                value => return invalid_argument(value, b"format"),
+            },
+            Opt::HideControlChars => deferred.hide_control_chars = Some(true),
+            Opt::HumanReadable => deferred.block_size = Some(BlockSize::human_readable(false)),
+            Opt::Hyperlink => match value {
+                Some(b"never" | b"no" | b"none") => {}
+                None | Some(b"always" | b"yes" | b"force" | b"auto" | b"tty" | b"if-tty") => {
+                    return unsupported_option(b"hyperlink")
+                }
+                Some(value) => return invalid_argument(value, b"hyperlink"),
+            },
+            Opt::Ignore => self.ignore_patterns.push(value.unwrap_or_default()),
+            Opt::IgnoreBackups => self.ignore_patterns.extend([&b"*~"[..], b".*~"]),
+            Opt::IndicatorStyle => match value.unwrap_or_default() {
+                b"none" => self.suffixes = Suffixes::None,
+                b"slash" => self.suffixes = Suffixes::Directories,
+                b"file-type" => self.suffixes = Suffixes::FileType,
+                b"classify" => self.suffixes = Suffixes::All,
+                value => return invalid_argument(value, b"indicator-style"),
+            },
+            Opt::Inode => self.print_inode = true,
+            Opt::Kibibytes => deferred.block_size_is_kilobytes = true,
+            Opt::Literal => deferred.quoting_style = Some(QuotingStyle::Literal),
+            Opt::Long => self.display_mode = DisplayMode::Long,
+            Opt::LongWithoutGroup => {
+                self.display_mode = DisplayMode::Long;
+                self.print_group = false;
+            }
+            Opt::LongWithoutOwner => {
+                self.display_mode = DisplayMode::Long;
+                self.print_owner = false;
+            }
+            Opt::NoGroup => self.print_group = false,
+            Opt::NoSort => {
+                self.sort_field = None;
+                self.show_all = ShowAll::Yes;
+            }
+            Opt::NumericUidGid => {
+                self.display_mode = DisplayMode::Long;
+                self.convert_id_to_name = false;
+            }
+            Opt::OnePerLine => {
+                if self.display_mode != DisplayMode::Long {
+                    self.display_mode = DisplayMode::SingleColumn;
+                }
+            }
+            Opt::QuoteName => deferred.quoting_style = Some(QuotingStyle::C),
+            Opt::QuotingStyle => {
+                let name = value.unwrap_or_default();
+                match QuotingStyle::from_name(name) {
+                    Some(style) => deferred.quoting_style = Some(style),
+                    None => return invalid_argument(name, b"quoting-style"),
+                }
+            }
+            Opt::Recursive => self.recurse = true,
+            Opt::RelativeTime => self.relative_time = true,
+            Opt::Reverse => self.reverse_sorting = true,
+            Opt::Rows => {
+                self.display_mode = DisplayMode::Grid(0);
+                self.grid_sort_direction = SortDirection::Horizontal;
+                deferred.grid_requested = true;
+            }
+            Opt::ShowControlChars => deferred.hide_control_chars = Some(false),
+            Opt::Si => deferred.block_size = Some(BlockSize::human_readable(true)),
+            Opt::Size => self.display_size_in_blocks = true,
+            Opt::SizeSort => self.sort_field = Some(SortField::Size),
+            Opt::Sort => {
+                self.sort_field = match value.unwrap_or_default() {
+                    b"none" => None,
+                    b"size" => Some(SortField::Size),
+                    b"time" => Some(SortField::Time),
+                    b"version" => Some(SortField::Version),
+                    b"extension" => Some(SortField::Extension),
+                    b"width" => Some(SortField::Width),
+                    value => return invalid_argument(value, b"sort"),
+                }
+            }
+            Opt::Slash => self.suffixes = Suffixes::Directories,
+            Opt::TabSize => match parse_columns(value.unwrap_or_default()) {
+                Some(size) => self.tab_size = size,
+                None => {
+                    error!("invalid tab size: \'", value.unwrap_or_default(), "\'\n");
+                    return false;
+                }
+            },
+            Opt::AtimeSort => {
+                self.time_field = TimeField::Accessed;
+                self.sort_field = Some(SortField::Time);
+            }
+            Opt::Time => match value.unwrap_or_default() {
+                b"atime" | b"access" | b"use" => self.time_field = TimeField::Accessed,
+                b"ctime" | b"status" => self.time_field = TimeField::StatusChanged,
+                b"mtime" | b"modification" => self.time_field = TimeField::Modified,
+                b"birth" | b"creation" => self.time_field = TimeField::Birth,
+                value => return invalid_argument(value, b"time"),
+            },
+            Opt::TimeSort => {
+                self.time_field = TimeField::Modified;
+                self.sort_field = Some(SortField::Time);
+            }
+            Opt::FullTime => {
+                self.display_mode = DisplayMode::Long;
+                deferred.time_style = Some(b"full-iso");
+            }
+            Opt::GroupDirectoriesFirst => self.group_directories_first = true,
+            Opt::Help => deferred.print_help = true,
+            Opt::Hide => self.hide_patterns.push(value.unwrap_or_default()),
+            Opt::TimeStyle => deferred.time_style = value,
+            Opt::Unsorted => self.sort_field = None,
+            Opt::Version => deferred.print_version = true,
+            Opt::XattrIndicator => self.show_xattr_indicator = true,
+            Opt::VersionSort => self.sort_field = Some(SortField::Version),
+            Opt::Width => match parse_columns(value.unwrap_or_default()) {
+                Some(width) => deferred.line_width = Some(width),
+                None => {
+                    error!("invalid line width: \'", value.unwrap_or_default(), "\'\n");
+                    return false;
+                }
+            },
+            Opt::ExtensionSort => self.sort_field = Some(SortField::Extension),
+            // Like GNU ls, this also turns off what would make the output harder to split,
+            // though later options can turn it back on
+            Opt::Zero => {
+                self.end_of_line = 0;
+                self.color = Color::Never;
+                deferred.quoting_style = Some(QuotingStyle::Literal);
+                deferred.hide_control_chars = Some(false);
+                if self.display_mode != DisplayMode::Long {
+                    self.display_mode = DisplayMode::SingleColumn;
+                }
+            }
+        }
+        true
+    }
+
+    // veneer doesn't give us the environment, but we can read it back from /proc
+    #[inline(never)]
+    fn init_environ() -> &'static [u8] {
+        crate::utils::fs_read(CStr::from_bytes(b"/proc/self/environ\0"))
+            .map(|contents| &*alloc::boxed::Box::leak(contents.into_boxed_slice()))
+            .unwrap_or_default()
+    }
+
+    // Whether an entry is left out by --ignore or --hide, the latter of which -a and -A override
+    pub fn ignores(&self, name: &[u8]) -> bool {
+        let hide_patterns = match self.show_all {
+            ShowAll::No => &self.hide_patterns[..],
+            _ => &[],
+        };
+        self.ignore_patterns
+            .iter()
+            .chain(hide_patterns)
+            .any(|pattern| crate::utils::fnmatch(pattern, name))
+    }
+
+    pub fn getenv(&self, name: &[u8]) -> Option<&'static [u8]> {
+        self.environ
+            .split(|b| *b == 0)
+            .find_map(|var| var.strip_prefix(name)?.strip_prefix(b"="))
+    }
+
+    // The locale for a category like LC_TIME, which LC_ALL overrides and LANG is the fallback for
+    fn locale_name(&self, category: &[u8]) -> &'static [u8] {
+        [&b"LC_ALL"[..], category, b"LANG"]
+            .iter()
+            .find_map(|name| self.getenv(name).filter(|value| !value.is_empty()))
+            .unwrap_or(b"C")
+    }
+
+    pub fn convert_to_localtime(&self, time: i64) -> crate::time::LocalTime<'_> {
         self.tzinfo.as_ref().unwrap().convert_to_localtime(time)
     }
 
@@ -384,22 +1456,49 @@ impl App {
             .unwrap_or_default()
     }
 
-    pub fn convert_status(&self, status: libc::stat64) -> crate::Status {
+    pub fn stat(
+        &self,
+        dirfd: libc::c_int,
+        name: CStr<'_>,
+        follow_symlinks: bool,
+    ) -> Result<crate::Status, Error> {
+        let flags = if follow_symlinks {
+            0
+        } else {
+            libc::AT_SYMLINK_NOFOLLOW
+        };
+        crate::utils::statx(dirfd, name, flags, self.statx_mask)
+            .map(|status| self.convert_status(status))
+    }
+
+    fn convert_status(&self, status: libc::statx) -> crate::Status {
         use TimeField::*;
+        let time = match self.time_field {
+            Accessed => Some(status.stx_atime),
+            Modified => Some(status.stx_mtime),
+            StatusChanged => Some(status.stx_ctime),
+            // Plenty of filesystems don't record when a file was created
+            Birth => (status.stx_mask & libc::STATX_BTIME != 0).then_some(status.stx_btime),
+        }
+        .map_or((-1, -1), |t| (t.tv_sec, t.tv_nsec as libc::c_long));
         crate::Status {
-            device: status.st_dev,
-            links: status.st_nlink,
-            mode: status.st_mode,
-            size: status.st_size,
-            blocks: status.st_blocks,
-            block_size: status.st_blksize,
-            uid: status.st_uid,
-            gid: status.st_gid,
-            inode: status.st_ino,
-            time: match self.time_field {
-                Accessed => status.st_atime,
-                Modified => status.st_mtime,
-                StatusChanged => status.st_ctime,
+            device: unsafe { libc::makedev(status.stx_dev_major, status.stx_dev_minor) },
+            rdev: unsafe { libc::makedev(status.stx_rdev_major, status.stx_rdev_minor) },
+            links: status.stx_nlink as libc::nlink_t,
+            mode: status.stx_mode as libc::mode_t,
+            size: status.stx_size as libc::off_t,
+            blocks: status.stx_blocks as libc::blkcnt64_t,
+            block_size: status.stx_blksize as libc::blksize_t,
+            uid: status.stx_uid,
+            gid: status.stx_gid,
+            inode: status.stx_ino as libc::ino_t,
+            time: time.0,
+            time_nsec: time.1,
+            shown_times: if self.show_all_times {
+                [status.stx_mtime, status.stx_ctime, status.stx_atime]
+                    .map(|t| (t.tv_sec, t.tv_nsec as libc::c_long))
+            } else {
+                [time; 3]
             },
         }
     }
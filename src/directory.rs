@@ -1,5 +1,5 @@
 use crate::{
-    cli::{App, Color, FollowSymlinks},
+    cli::{App, Color, FollowSymlinks, Suffixes},
     utils::memcmp,
     Style,
 };
@@ -29,42 +29,75 @@ pub trait DirEntryExt {
     fn style(&self, dir: &Directory, app: &App) -> (Style, Option<u8>);
     fn inode(&self) -> u64;
     fn blocks(&self) -> u64;
-    fn time(&self) -> libc::time_t;
+    fn time(&self) -> (libc::time_t, libc::c_long);
     fn d_type(&self) -> DType;
     fn size(&self) -> libc::off_t;
 }
 
+// The file types of dircolors. Regular files carry whether they are executable, because -F shows
+// that even when they are colored for another reason.
 #[derive(Clone, Copy)]
 pub enum EntryType {
     Directory,
+    Sticky,
+    OtherWritable,
+    StickyOtherWritable,
+    Regular(bool),
     Executable,
-    Regular,
+    Setuid(bool),
+    Setgid(bool),
+    Capability(bool),
+    MultiHardlink(bool),
     Link,
     BrokenLink,
+    Missing,
     Fifo,
     Socket,
+    BlockDevice,
+    CharDevice,
     Other,
 }
 
 impl EntryType {
     fn style(self, app: &App) -> (Option<Style>, Option<u8>) {
-        use crate::cli::Suffixes;
         use EntryType::*;
-        use Style::*;
-        match (self, app.suffixes) {
-            (Directory, Suffixes::None) => (Some(BlueBold), None),
-            (Directory, _) => (Some(BlueBold), Some(b'/')),
-            (Executable, Suffixes::All) => (Some(GreenBold), Some(b'*')),
-            (Executable, _) => (Some(GreenBold), None),
-            (Regular, _) => (None, None),
-            (Link, Suffixes::All) => (Some(CyanBold), Some(b'@')),
-            (Link, _) => (Some(CyanBold), None),
-            (BrokenLink, Suffixes::All) => (Some(RedBold), Some(b'@')),
-            (BrokenLink, _) => (Some(RedBold), None),
-            (Fifo, Suffixes::All) => (Some(YellowBold), Some(b'|')),
-            (Socket, _) => (Some(MagentaBold), None),
-            (Fifo | Other, _) => (Some(YellowBold), None),
-        }
+        let colors = &app.ls_colors;
+        let style = match self {
+            Directory => Some(colors.directory),
+            Sticky => colors.sticky,
+            OtherWritable => colors.other_writable,
+            StickyOtherWritable => colors.sticky_other_writable,
+            Regular(_) => None,
+            Executable => colors.executable,
+            Setuid(_) => colors.setuid,
+            Setgid(_) => colors.setgid,
+            Capability(_) => colors.capability,
+            MultiHardlink(_) => colors.multi_hardlink,
+            Link => Some(colors.link),
+            BrokenLink | Other => Some(colors.orphan),
+            Missing => colors.missing,
+            Fifo => Some(colors.fifo),
+            Socket => Some(colors.socket),
+            BlockDevice => Some(colors.block_device),
+            CharDevice => Some(colors.char_device),
+        };
+        let suffix = match (self, app.suffixes) {
+            (_, Suffixes::None) => None,
+            (Directory | Sticky | OtherWritable | StickyOtherWritable, _) => Some(b'/'),
+            (_, Suffixes::Directories) => None,
+            (Link | BrokenLink, _) => Some(b'@'),
+            (Fifo, _) => Some(b'|'),
+            (Socket, _) => Some(b'='),
+            (_, Suffixes::FileType) => None,
+            (Executable, _) => Some(b'*'),
+            (
+                Regular(true) | Setuid(true) | Setgid(true) | Capability(true)
+                | MultiHardlink(true),
+                _,
+            ) => Some(b'*'),
+            _ => None,
+        };
+        (style, suffix)
     }
 }
 
@@ -85,11 +118,11 @@ impl<'a> DirEntryExt for (DirEntry<'a>, Option<crate::Status>) {
         }
     }
 
-    fn time(&self) -> libc::time_t {
+    fn time(&self) -> (libc::time_t, libc::c_long) {
         if let Some(st) = &self.1 {
-            st.time
+            (st.time, st.time_nsec)
         } else {
-            0
+            (0, 0)
         }
     }
 
@@ -109,109 +142,145 @@ impl<'a> DirEntryExt for (DirEntry<'a>, Option<crate::Status>) {
         use EntryType::*;
 
         // Deduce the correct entry type
+        let follow_symlinks = app.follow_symlinks == FollowSymlinks::Always;
         let entry_type = if let Some(status) = &self.1 {
-            entry_type_from_status(status)
-        } else if app.color == Color::Never && app.suffixes == crate::cli::Suffixes::None {
+            entry_type_from_status(status, dir, self.name(), app)
+        } else if app.color == Color::Never && app.suffixes == Suffixes::None {
             // DO nothing extra if no colors and no suffixes are required
-            Regular
-        } else if app.color == Color::Auto {
-            match self.0.d_type {
-                DType::DIR => Directory,
-                DType::FIFO => Fifo,
-                DType::SOCK => Socket,
-                DType::CHR | DType::BLK => Other,
-                DType::LNK => Link,
-                DType::REG | DType::UNKNOWN => Regular,
+            Regular(false)
+        } else if matches!(self.0.d_type, DType::DIR | DType::REG)
+            && app.suffixes != Suffixes::All
+            && (app.color == Color::Never || !app.ls_colors.needs_mode())
+        {
+            // Nothing about these depends on their mode, so there's no need to stat them
+            if self.0.d_type == DType::DIR {
+                Directory
+            } else {
+                Regular(false)
             }
         } else {
             match self.0.d_type {
-                DType::DIR => Directory,
                 DType::FIFO => Fifo,
                 DType::SOCK => Socket,
-                DType::CHR | DType::BLK => Other,
-                DType::REG => syscalls::faccessat(dir.raw_fd(), self.name(), libc::X_OK)
-                    .map(|_| Executable)
-                    .unwrap_or(Regular),
-                DType::LNK => syscalls::faccessat(dir.raw_fd(), self.name(), libc::F_OK)
-                    .map(|_| Link)
-                    .unwrap_or(BrokenLink),
-                DType::UNKNOWN => if app.follow_symlinks == FollowSymlinks::Always {
-                    syscalls::fstatat(dir.raw_fd(), self.0.name)
-                } else {
-                    syscalls::lstatat(dir.raw_fd(), self.0.name)
+                DType::CHR => CharDevice,
+                DType::BLK => BlockDevice,
+                DType::LNK if !follow_symlinks => {
+                    syscalls::faccessat(dir.raw_fd(), self.name(), libc::F_OK)
+                        .map(|_| Link)
+                        .unwrap_or(BrokenLink)
                 }
-                .map(|status| {
-                    let status = app.convert_status(status);
-                    let entry_type = status.mode & libc::S_IFMT;
-                    if entry_type == libc::S_IFDIR {
-                        Directory
-                    } else if entry_type == libc::S_IFIFO {
-                        Fifo
-                    } else if entry_type == libc::S_IFLNK {
-                        if app.color == Color::Always
-                            && syscalls::faccessat(dir.raw_fd(), self.0.name, libc::F_OK).is_err()
-                        {
-                            BrokenLink
-                        } else {
-                            Link
-                        }
-                    } else if status.mode & libc::S_IXUSR > 0 {
-                        Executable
+                // Directories and files are colored by their permissions, and links we follow
+                // by what they point to
+                _ => app
+                    .stat(dir.raw_fd(), self.0.name, follow_symlinks)
+                    .map(|status| entry_type_from_status(&status, dir, self.name(), app))
+                    .unwrap_or(if self.0.d_type == DType::LNK {
+                        BrokenLink
                     } else {
-                        Regular
-                    }
-                })
-                .unwrap_or(BrokenLink),
+                        Missing
+                    }),
             }
         };
 
         let (style, suffix) = entry_type.style(app);
 
         if app.color == Color::Never {
-            return (Style::Reset, suffix);
+            return (Style::RESET, suffix);
         }
 
         if let Some(style) = style {
             (style, suffix)
         } else {
-            (extension_style(self.name().as_bytes()), suffix)
+            (app.ls_colors.file_style(self.name().as_bytes()), suffix)
         }
     }
 }
 
+// With -F or --file-type, long listings mark what a symlink points to after the target
+pub fn link_target_suffix(dir: &Directory, name: CStr, app: &App) -> Option<u8> {
+    if !matches!(app.suffixes, Suffixes::FileType | Suffixes::All) {
+        return None;
+    }
+    let status = app.stat(dir.raw_fd(), name, true).ok()?;
+    entry_type_from_status(&status, dir, name, app).style(app).1
+}
+
 include!(concat!(env!("OUT_DIR"), "/codegen.rs"));
 
 pub fn extension_style(name: &[u8]) -> Style {
     if name.first() == Some(&b'#') || name.last() == Some(&b'~') || name.last() == Some(&b'#') {
-        return Style::Fixed(244);
+        return Style::GRAY;
     }
     let extension = match name.rsplit(|b| *b == b'.').next() {
-        None => return Style::White,
+        None => return Style::WHITE,
         Some(ext) => ext,
     };
     if let Ok(i) = EXTENSION_STYLES.binary_search_by(|probe| memcmp(probe.0, extension)) {
         EXTENSION_STYLES[i].1
     } else {
-        Style::White
+        Style::WHITE
     }
 }
 
-fn entry_type_from_status(status: &crate::Status) -> EntryType {
+// Like GNU ls, a type that isn't colored falls through to the next one that applies
+fn entry_type_from_status(
+    status: &crate::Status,
+    dir: &Directory,
+    name: CStr,
+    app: &App,
+) -> EntryType {
     use EntryType::*;
-    let entry_type = status.mode & libc::S_IFMT;
-    if entry_type == libc::S_IFDIR {
-        Directory
-    } else if entry_type == libc::S_IFIFO {
-        Fifo
-    } else if entry_type == libc::S_IFSOCK {
-        Socket
-    } else if entry_type == libc::S_IFLNK {
-        Link
-    } else if status.mode & libc::S_IXUSR > 0 {
-        Executable
-    } else if entry_type == libc::S_IFREG {
-        Regular
-    } else {
-        Other
+    let colors = &app.ls_colors;
+    let mode = status.mode;
+    match mode & libc::S_IFMT {
+        libc::S_IFREG => {
+            let executable = mode & (libc::S_IXUSR | libc::S_IXGRP | libc::S_IXOTH) != 0;
+            if mode & libc::S_ISUID != 0 && colors.setuid.is_some() {
+                Setuid(executable)
+            } else if mode & libc::S_ISGID != 0 && colors.setgid.is_some() {
+                Setgid(executable)
+            } else if colors.capability.is_some() && has_capability(dir, name) {
+                Capability(executable)
+            } else if executable && colors.executable.is_some() {
+                Executable
+            } else if status.links > 1 && colors.multi_hardlink.is_some() {
+                MultiHardlink(executable)
+            } else {
+                Regular(executable)
+            }
+        }
+        libc::S_IFDIR => {
+            let sticky = mode & libc::S_ISVTX != 0;
+            let other_writable = mode & libc::S_IWOTH != 0;
+            if sticky && other_writable && colors.sticky_other_writable.is_some() {
+                StickyOtherWritable
+            } else if other_writable && colors.other_writable.is_some() {
+                OtherWritable
+            } else if sticky && colors.sticky.is_some() {
+                Sticky
+            } else {
+                Directory
+            }
+        }
+        libc::S_IFLNK => {
+            if app.color == Color::Always
+                && syscalls::faccessat(dir.raw_fd(), name, libc::F_OK).is_err()
+            {
+                BrokenLink
+            } else {
+                Link
+            }
+        }
+        libc::S_IFIFO => Fifo,
+        libc::S_IFSOCK => Socket,
+        libc::S_IFBLK => BlockDevice,
+        libc::S_IFCHR => CharDevice,
+        _ => Other,
     }
 }
+
+fn has_capability(dir: &Directory, name: CStr) -> bool {
+    let mut value = [0; 64];
+    crate::utils::lgetxattr(dir.raw_fd(), name, b"security.capability\0", &mut value)
+        .is_ok_and(|len| len > 0)
+}
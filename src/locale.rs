@@ -0,0 +1,536 @@
+use crate::CStr;
+use alloc::{boxed::Box, vec::Vec};
+
+// Month and day names and the order of dates for LC_TIME. Common locales are built in, the rest
+// are read from the glibc locale sources, since without a libc there are no compiled locales.
+pub struct TimeLocale {
+    pub abbreviated_months: [&'static [u8]; 12],
+    pub months: [&'static [u8]; 12],
+    pub abbreviated_days: [&'static [u8]; 7],
+    pub days: [&'static [u8]; 7],
+    pub date_order: DateOrder,
+    is_posix: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DateOrder {
+    MonthDay,
+    DayMonth,
+    YearMonthDay,
+}
+
+struct Names {
+    abbreviated_months: [&'static str; 12],
+    months: [&'static str; 12],
+    abbreviated_days: [&'static str; 7],
+    days: [&'static str; 7],
+}
+
+const C: Names = Names {
+    abbreviated_months: [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ],
+    months: [
+        "January",
+        "February",
+        "March",
+        "April",
+        "May",
+        "June",
+        "July",
+        "August",
+        "September",
+        "October",
+        "November",
+        "December",
+    ],
+    abbreviated_days: ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"],
+    days: [
+        "Sunday",
+        "Monday",
+        "Tuesday",
+        "Wednesday",
+        "Thursday",
+        "Friday",
+        "Saturday",
+    ],
+};
+
+const DE: Names = Names {
+    abbreviated_months: [
+        "Jan", "Feb", "Mär", "Apr", "Mai", "Jun", "Jul", "Aug", "Sep", "Okt", "Nov", "Dez",
+    ],
+    months: [
+        "Januar",
+        "Februar",
+        "März",
+        "April",
+        "Mai",
+        "Juni",
+        "Juli",
+        "August",
+        "September",
+        "Oktober",
+        "November",
+        "Dezember",
+    ],
+    abbreviated_days: ["So", "Mo", "Di", "Mi", "Do", "Fr", "Sa"],
+    days: [
+        "Sonntag",
+        "Montag",
+        "Dienstag",
+        "Mittwoch",
+        "Donnerstag",
+        "Freitag",
+        "Samstag",
+    ],
+};
+
+const ES: Names = Names {
+    abbreviated_months: [
+        "ene", "feb", "mar", "abr", "may", "jun", "jul", "ago", "sep", "oct", "nov", "dic",
+    ],
+    months: [
+        "enero",
+        "febrero",
+        "marzo",
+        "abril",
+        "mayo",
+        "junio",
+        "julio",
+        "agosto",
+        "septiembre",
+        "octubre",
+        "noviembre",
+        "diciembre",
+    ],
+    abbreviated_days: ["dom", "lun", "mar", "mié", "jue", "vie", "sáb"],
+    days: [
+        "domingo",
+        "lunes",
+        "martes",
+        "miércoles",
+        "jueves",
+        "viernes",
+        "sábado",
+    ],
+};
+
+const FR: Names = Names {
+    abbreviated_months: [
+        "janv.", "févr.", "mars", "avril", "mai", "juin", "juil.", "août", "sept.", "oct.", "nov.",
+        "déc.",
+    ],
+    months: [
+        "janvier",
+        "février",
+        "mars",
+        "avril",
+        "mai",
+        "juin",
+        "juillet",
+        "août",
+        "septembre",
+        "octobre",
+        "novembre",
+        "décembre",
+    ],
+    abbreviated_days: ["dim.", "lun.", "mar.", "mer.", "jeu.", "ven.", "sam."],
+    days: [
+        "dimanche", "lundi", "mardi", "mercredi", "jeudi", "vendredi", "samedi",
+    ],
+};
+
+const IT: Names = Names {
+    abbreviated_months: [
+        "gen", "feb", "mar", "apr", "mag", "giu", "lug", "ago", "set", "ott", "nov", "dic",
+    ],
+    months: [
+        "gennaio",
+        "febbraio",
+        "marzo",
+        "aprile",
+        "maggio",
+        "giugno",
+        "luglio",
+        "agosto",
+        "settembre",
+        "ottobre",
+        "novembre",
+        "dicembre",
+    ],
+    abbreviated_days: ["dom", "lun", "mar", "mer", "gio", "ven", "sab"],
+    days: [
+        "domenica",
+        "lunedì",
+        "martedì",
+        "mercoledì",
+        "giovedì",
+        "venerdì",
+        "sabato",
+    ],
+};
+
+const NL: Names = Names {
+    abbreviated_months: [
+        "jan", "feb", "mrt", "apr", "mei", "jun", "jul", "aug", "sep", "okt", "nov", "dec",
+    ],
+    months: [
+        "januari",
+        "februari",
+        "maart",
+        "april",
+        "mei",
+        "juni",
+        "juli",
+        "augustus",
+        "september",
+        "oktober",
+        "november",
+        "december",
+    ],
+    abbreviated_days: ["zo", "ma", "di", "wo", "do", "vr", "za"],
+    days: [
+        "zondag",
+        "maandag",
+        "dinsdag",
+        "woensdag",
+        "donderdag",
+        "vrijdag",
+        "zaterdag",
+    ],
+};
+
+const PT: Names = Names {
+    abbreviated_months: [
+        "jan", "fev", "mar", "abr", "mai", "jun", "jul", "ago", "set", "out", "nov", "dez",
+    ],
+    months: [
+        "janeiro",
+        "fevereiro",
+        "março",
+        "abril",
+        "maio",
+        "junho",
+        "julho",
+        "agosto",
+        "setembro",
+        "outubro",
+        "novembro",
+        "dezembro",
+    ],
+    abbreviated_days: ["dom", "seg", "ter", "qua", "qui", "sex", "sáb"],
+    days: [
+        "domingo", "segunda", "terça", "quarta", "quinta", "sexta", "sábado",
+    ],
+};
+
+const SV: Names = Names {
+    abbreviated_months: [
+        "jan", "feb", "mar", "apr", "maj", "jun", "jul", "aug", "sep", "okt", "nov", "dec",
+    ],
+    months: [
+        "januari",
+        "februari",
+        "mars",
+        "april",
+        "maj",
+        "juni",
+        "juli",
+        "augusti",
+        "september",
+        "oktober",
+        "november",
+        "december",
+    ],
+    abbreviated_days: ["sön", "mån", "tis", "ons", "tor", "fre", "lör"],
+    days: [
+        "söndag", "måndag", "tisdag", "onsdag", "torsdag", "fredag", "lördag",
+    ],
+};
+
+// The name, LC_TIME and the LC_NUMERIC thousands separator of a built-in locale
+type Builtin = (&'static str, &'static Names, DateOrder, &'static str);
+
+// When neither the locale nor its definition file is known, the first entry with the same
+// language is used
+static BUILTIN: &[Builtin] = {
+    use DateOrder::*;
+    &[
+        ("de_DE", &DE, DayMonth, "."),
+        ("en_US", &C, MonthDay, ","),
+        ("en_AU", &C, DayMonth, ","),
+        ("en_GB", &C, DayMonth, ","),
+        ("en_IE", &C, DayMonth, ","),
+        ("en_NZ", &C, DayMonth, ","),
+        ("es_ES", &ES, DayMonth, "."),
+        ("fr_FR", &FR, DayMonth, "\u{202f}"),
+        ("it_IT", &IT, DayMonth, "."),
+        ("nl_NL", &NL, DayMonth, "."),
+        ("pt_BR", &PT, DayMonth, "."),
+        ("pt_PT", &PT, DayMonth, ""),
+        ("sv_SE", &SV, YearMonthDay, "\u{202f}"),
+    ]
+};
+
+// Looks a locale like de_DE.UTF-8@euro up in the built-in table and the definition files. None
+// means the POSIX locale.
+fn find<T>(name: &[u8], builtin: fn(&Builtin) -> T, load: fn(&[u8]) -> Option<T>) -> Option<T> {
+    let (name, modifier) = match name.iter().position(|b| *b == b'@') {
+        Some(i) => (&name[..i], Some(&name[i..])),
+        None => (name, None),
+    };
+    let name = name.split(|b| *b == b'.').next().unwrap_or_default();
+    let language = name.split(|b| *b == b'_').next().unwrap_or_default();
+    if matches!(name, b"" | b"C" | b"POSIX") || name.contains(&b'/') {
+        return None;
+    }
+
+    let find_builtin = |matches: &dyn Fn(&[u8]) -> bool| {
+        BUILTIN
+            .iter()
+            .find(|entry| matches(entry.0.as_bytes()))
+            .map(builtin)
+    };
+    let mut with_modifier = name.to_vec();
+    with_modifier.extend_from_slice(modifier.unwrap_or_default());
+    find_builtin(&|key| key == name)
+        .or_else(|| load(&with_modifier))
+        .or_else(|| load(name))
+        .or_else(|| find_builtin(&|key| key.split(|b| *b == b'_').next() == Some(language)))
+}
+
+// The thousands separator of an LC_NUMERIC locale, for block sizes with the ' flag. The POSIX
+// locale doesn't have one.
+pub fn thousands_separator(name: &[u8]) -> &'static [u8] {
+    let load = |name: &[u8]| {
+        let mut separator = &b""[..];
+        read_section(name, b"LC_NUMERIC", 0, &mut |keyword, strings| {
+            if keyword == b"thousands_sep" {
+                separator = Box::leak(strings.first()?.clone().into_boxed_slice());
+            }
+            Some(())
+        })?;
+        Some(separator)
+    };
+    find(name, |entry| entry.3.as_bytes(), load).unwrap_or_default()
+}
+
+impl DateOrder {
+    // The old and recent formats of the locale time style. These are fls's own, not the
+    // translations shipped with coreutils: only the order of d_fmt is used, and the year of old
+    // dates and the time of recent ones share a slot so the month and day line up in a column.
+    pub fn formats(self) -> (&'static [u8], &'static [u8]) {
+        match self {
+            DateOrder::MonthDay => (b"%b %e  %Y", b"%b %e %H:%M"),
+            DateOrder::DayMonth => (b"%e %b  %Y", b"%e %b %H:%M"),
+            DateOrder::YearMonthDay => (b" %Y %b %e", b"%H:%M %b %e"),
+        }
+    }
+}
+
+impl TimeLocale {
+    pub fn posix() -> Self {
+        Self::from_names(&C, DateOrder::MonthDay)
+    }
+
+    fn from_names(names: &Names, date_order: DateOrder) -> Self {
+        TimeLocale {
+            abbreviated_months: names.abbreviated_months.map(str::as_bytes),
+            months: names.months.map(str::as_bytes),
+            abbreviated_days: names.abbreviated_days.map(str::as_bytes),
+            days: names.days.map(str::as_bytes),
+            date_order,
+            is_posix: true,
+        }
+    }
+
+    pub fn is_posix(&self) -> bool {
+        self.is_posix
+    }
+
+    // Takes a name like de_DE.UTF-8@euro, as found in LC_ALL, LC_TIME or LANG
+    pub fn new(name: &[u8]) -> Self {
+        let locale = find(
+            name,
+            |(_, names, date_order, _)| Self::from_names(names, *date_order),
+            Self::load,
+        );
+        // Like glibc, a locale we know nothing about is the POSIX one
+        match locale {
+            Some(mut locale) => {
+                locale.is_posix = false;
+                locale.abbreviated_months = pad_months(locale.abbreviated_months);
+                locale
+            }
+            None => Self::posix(),
+        }
+    }
+
+    fn load(name: &[u8]) -> Option<Self> {
+        let mut locale = Self::posix();
+        read_section(name, b"LC_TIME", 0, &mut |keyword, strings| {
+            match keyword {
+                b"abmon" => locale.abbreviated_months = leak_strings(strings)?,
+                b"mon" => locale.months = leak_strings(strings)?,
+                b"abday" => locale.abbreviated_days = leak_strings(strings)?,
+                b"day" => locale.days = leak_strings(strings)?,
+                b"d_fmt" => locale.date_order = date_order(strings.first()?),
+                _ => {}
+            }
+            Some(())
+        })?;
+        Some(locale)
+    }
+}
+
+// Gets each keyword of a section with its values, and returns None to stop reading
+type Visit<'a> = dyn FnMut(&[u8], &[Vec<u8>]) -> Option<()> + 'a;
+
+// Passes each keyword and its values in one section of a file in /usr/share/i18n/locales to visit,
+// following copy into the files it names. None if the section isn't there or visit gives up.
+fn read_section(name: &[u8], section: &[u8], depth: usize, visit: &mut Visit) -> Option<()> {
+    if depth > 4 {
+        return None;
+    }
+    let mut path = b"/usr/share/i18n/locales/".to_vec();
+    path.extend_from_slice(name);
+    path.push(0);
+    let source = crate::utils::fs_read(CStr::from_bytes(&path)).ok()?;
+
+    let mut comment = b'#';
+    let mut escape = b'\\';
+    let mut in_section = false;
+    let mut line = Vec::new();
+    for raw_line in source.split(|b| *b == b'\n') {
+        // A line that ends with the escape character continues on the next one
+        let raw_line = raw_line.trim_ascii();
+        if let Some(continued) = raw_line.strip_suffix(&[escape]) {
+            line.extend_from_slice(continued);
+            continue;
+        }
+        line.extend_from_slice(raw_line);
+        let logical = core::mem::take(&mut line);
+        if logical.first() == Some(&comment) {
+            continue;
+        }
+        let (keyword, value) = match logical.iter().position(u8::is_ascii_whitespace) {
+            Some(i) => (&logical[..i], logical[i..].trim_ascii()),
+            None => (&logical[..], &b""[..]),
+        };
+
+        if !in_section {
+            match keyword {
+                b"comment_char" => comment = value.first().copied().unwrap_or(comment),
+                b"escape_char" => escape = value.first().copied().unwrap_or(escape),
+                _ => in_section = keyword == section,
+            }
+            continue;
+        }
+
+        let strings = parse_strings(value, escape);
+        match keyword {
+            b"END" => break,
+            b"copy" => read_section(strings.first()?, section, depth + 1, visit)?,
+            _ => visit(keyword, &strings)?,
+        }
+    }
+    in_section.then_some(())
+}
+
+// Values are lists like "Jan";"F<U00E9>v", where <Uxxxx> is a code point
+fn parse_strings(value: &[u8], escape: u8) -> Vec<Vec<u8>> {
+    let mut strings = Vec::new();
+    let mut current = Vec::new();
+    let mut in_quotes = false;
+    let mut bytes = value.iter().copied();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'"' => in_quotes = !in_quotes,
+            b';' if !in_quotes => strings.push(core::mem::take(&mut current)),
+            b'<' => {
+                let symbol: Vec<u8> = bytes.by_ref().take_while(|b| *b != b'>').collect();
+                let code_point = symbol
+                    .strip_prefix(b"U")
+                    .and_then(|hex| core::str::from_utf8(hex).ok())
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32);
+                if let Some(c) = code_point {
+                    let mut buf = [0; 4];
+                    current.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+            }
+            b if b == escape => current.extend(bytes.next()),
+            b if in_quotes => current.push(b),
+            _ => {}
+        }
+    }
+    strings.push(current);
+    strings
+}
+
+fn leak_strings<const N: usize>(strings: &[Vec<u8>]) -> Option<[&'static [u8]; N]> {
+    if strings.len() != N {
+        return None;
+    }
+    let slab: &'static [u8] = Box::leak(strings.concat().into_boxed_slice());
+    let mut rest = slab;
+    let mut leaked = [&b""[..]; N];
+    for (leaked, string) in leaked.iter_mut().zip(strings) {
+        let (this, next) = rest.split_at(string.len());
+        *leaked = this;
+        rest = next;
+    }
+    Some(leaked)
+}
+
+// d_fmt is the locale's %x, like %d.%m.%Y, which tells us which part of a date comes first
+fn date_order(format: &[u8]) -> DateOrder {
+    let mut order = Vec::new();
+    let mut bytes = format.iter();
+    while let Some(b) = bytes.next() {
+        if *b != b'%' {
+            continue;
+        }
+        match bytes.next() {
+            Some(b'd' | b'e') => order.push(b'd'),
+            Some(b'm' | b'b' | b'h' | b'B') => order.push(b'm'),
+            Some(b'Y' | b'y' | b'C' | b'F') => order.push(b'y'),
+            Some(b'D') => order.push(b'm'),
+            _ => {}
+        }
+    }
+    let position = |part| order.iter().position(|p| *p == part).unwrap_or(usize::MAX);
+    if position(b'y') < position(b'm') && position(b'y') < position(b'd') {
+        DateOrder::YearMonthDay
+    } else if position(b'd') < position(b'm') {
+        DateOrder::DayMonth
+    } else {
+        DateOrder::MonthDay
+    }
+}
+
+// Like GNU ls, abbreviated months are padded to the same width so that dates line up
+fn pad_months(months: [&'static [u8]; 12]) -> [&'static [u8]; 12] {
+    let widths = months.map(crate::output::len_utf8);
+    let width = widths.iter().copied().max().unwrap_or(0);
+    if widths.iter().all(|w| *w == width) {
+        return months;
+    }
+    let align_right = months
+        .iter()
+        .all(|m| m.first().is_some_and(u8::is_ascii_digit));
+    let padded: Vec<Vec<u8>> = months
+        .iter()
+        .zip(widths)
+        .map(|(month, month_width)| {
+            let padding = core::iter::repeat_n(b' ', width - month_width);
+            if align_right {
+                padding.chain(month.iter().copied()).collect()
+            } else {
+                month.iter().copied().chain(padding).collect()
+            }
+        })
+        .collect();
+    leak_strings(&padded).unwrap_or(months)
+}
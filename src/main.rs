@@ -4,9 +4,12 @@
 
 extern crate alloc;
 
+mod block_size;
 mod cli;
 mod directory;
+mod locale;
 mod output;
+mod quote;
 mod style;
 mod time;
 mod utils;
@@ -80,21 +83,26 @@ fn main() -> Result<(), Error> {
 
     if !files.is_empty() {
         let dir = Directory::open(CStr::from_bytes(b".\0")).unwrap();
+        // -H follows symlinks given on the command line, but not the ones inside directories
+        let follow_symlinks = app.follow_symlinks != cli::FollowSymlinks::Never;
+        // Like GNU ls, a file named on the command line that can't be stat'd isn't listed at all
         if app.needs_details {
-            for e in &mut files {
-                let status = if app.follow_symlinks == cli::FollowSymlinks::Always {
-                    syscalls::fstatat(dir.raw_fd(), e.name())
-                } else {
-                    syscalls::lstatat(dir.raw_fd(), e.name())
-                }
-                .map(|status| app.convert_status(status));
-                match status {
-                    Ok(s) => e.1 = Some(s),
+            files.retain_mut(
+                |e| match app.stat(dir.raw_fd(), e.name(), follow_symlinks) {
+                    Ok(s) => {
+                        e.1 = Some(s);
+                        true
+                    }
                     Err(err) => {
                         access_error(&e.name(), err);
+                        false
                     }
-                }
-            }
+                },
+            );
+        }
+
+        if app.group_directories_first && !app.needs_details {
+            resolve_d_types(&mut files, &dir, follow_symlinks);
         }
 
         sort_entries(&mut files, &app);
@@ -128,27 +136,111 @@ fn main() -> Result<(), Error> {
 }
 
 fn sort_entries(entries: &mut [(DirEntry, Option<Status>)], app: &App) {
-    if let Some(field) = app.sort_field {
-        entries.sort_unstable_by(|a, b| {
-            let mut ordering = match field {
-                SortField::Time => b
-                    .time()
-                    .cmp(&a.time())
-                    .then_with(|| vercmp(a.name(), b.name())),
-                SortField::Size => {
-                    b.1.clone()
-                        .unwrap_or_default()
-                        .size
-                        .cmp(&a.1.clone().unwrap_or_default().size)
-                        .then_with(|| vercmp(a.name(), b.name()))
-                }
-                SortField::Name => vercmp(a.name(), b.name()),
-            };
-            if app.reverse_sorting {
-                ordering = ordering.reverse();
-            }
+    use core::cmp::Ordering;
+
+    // Like coreutils, ties are broken by name, except for -v, which uses a plain byte comparison
+    let by_name = |a: &(DirEntry, Option<Status>), b: &(DirEntry, Option<Status>)| {
+        vercmp(a.name().as_bytes(), b.name().as_bytes())
+    };
+
+    let field = match app.sort_field {
+        Some(field) => field,
+        None => return,
+    };
+
+    let is_dir = |e: &(DirEntry, Option<Status>)| match &e.1 {
+        Some(status) => status.mode & libc::S_IFMT == libc::S_IFDIR,
+        None => e.0.d_type == DType::DIR,
+    };
+
+    // Widths are passed in so --sort=width only has to quote each name once
+    let compare = |a: &(DirEntry, Option<Status>),
+                   b: &(DirEntry, Option<Status>),
+                   (width_a, width_b): (usize, usize)| {
+        // Directories come first regardless of -r
+        if app.group_directories_first && is_dir(a) != is_dir(b) {
+            return is_dir(b).cmp(&is_dir(a));
+        }
+
+        let ordering: Ordering = match field {
+            SortField::Time => b.time().cmp(&a.time()).then_with(|| by_name(a, b)),
+            SortField::Size => b.size().cmp(&a.size()).then_with(|| by_name(a, b)),
+            SortField::Name => by_name(a, b),
+            SortField::Version => filevercmp(a.name().as_bytes(), b.name().as_bytes())
+                .then_with(|| a.name().as_bytes().cmp(b.name().as_bytes())),
+            // Like coreutils in the C locale, extensions are compared byte by byte
+            SortField::Extension => extension(a.name().as_bytes())
+                .cmp(extension(b.name().as_bytes()))
+                .then_with(|| by_name(a, b)),
+            SortField::Width => width_a.cmp(&width_b).then_with(|| by_name(a, b)),
+        };
+        if app.reverse_sorting {
+            ordering.reverse()
+        } else {
             ordering
-        });
+        }
+    };
+
+    if field == SortField::Width {
+        // The width of each name as it will be printed
+        let mut quote_buf = Vec::new();
+        let widths: Vec<usize> = entries
+            .iter()
+            .map(|e| len_utf8(quote::quote_file_name(e.name().as_bytes(), app, &mut quote_buf).0))
+            .collect();
+        let mut order: Vec<usize> = (0..entries.len()).collect();
+        order.sort_unstable_by(|&i, &j| compare(&entries[i], &entries[j], (widths[i], widths[j])));
+        // Put the entries in that order. Entries before i have already been moved, so one that
+        // was swapped away is found by following where it went.
+        for i in 0..order.len() {
+            let mut j = order[i];
+            while j < i {
+                j = order[j];
+            }
+            entries.swap(i, j);
+        }
+    } else {
+        entries.sort_unstable_by(|a, b| compare(a, b, (0, 0)));
+    }
+}
+
+// --group-directories-first needs to know which entries are directories. When d_type doesn't tell
+// us, or the entry is a symlink we're following, that takes a stat.
+fn resolve_d_types(
+    entries: &mut [(DirEntry, Option<Status>)],
+    dir: &Directory,
+    follow_symlinks: bool,
+) {
+    for e in entries.iter_mut() {
+        let d_type = e.0.d_type;
+        if d_type != DType::UNKNOWN && !(follow_symlinks && d_type == DType::LNK) {
+            continue;
+        }
+        let flags = if follow_symlinks {
+            0
+        } else {
+            libc::AT_SYMLINK_NOFOLLOW
+        };
+        if let Ok(status) = utils::statx(dir.raw_fd(), e.0.name, flags, libc::STATX_TYPE) {
+            e.0.d_type = match status.stx_mode as libc::mode_t & libc::S_IFMT {
+                libc::S_IFDIR => DType::DIR,
+                libc::S_IFREG => DType::REG,
+                libc::S_IFLNK => DType::LNK,
+                libc::S_IFIFO => DType::FIFO,
+                libc::S_IFSOCK => DType::SOCK,
+                libc::S_IFCHR => DType::CHR,
+                libc::S_IFBLK => DType::BLK,
+                _ => DType::UNKNOWN,
+            };
+        }
+    }
+}
+
+// Everything from the last ".", or nothing if there isn't one
+fn extension(name: &[u8]) -> &[u8] {
+    match name.iter().rposition(|b| *b == b'.') {
+        Some(i) => &name[i..],
+        None => &[],
     }
 }
 
@@ -169,21 +261,25 @@ fn list_dir_contents(
     let mut entries = Vec::new();
     entries.reserve(hint.1.unwrap_or(hint.0));
 
+    // Filtering only needs names, so ignored entries are never stat'd
+    let contents = contents
+        .iter()
+        .filter(|e| !app.ignores(e.name().as_bytes()));
     match app.show_all {
         ShowAll::No => {
-            for e in contents.iter().filter(|e| e.name().get(0) != Some(b'.')) {
+            for e in contents.filter(|e| e.name().get(0) != Some(b'.')) {
                 entries.push((e.into(), None));
             }
         }
         ShowAll::Almost => {
-            for e in contents.iter() {
+            for e in contents {
                 if e.name().as_bytes() != b".." && e.name().as_bytes() != b"." {
                     entries.push((e.into(), None));
                 }
             }
         }
         ShowAll::Yes => {
-            for e in contents.iter() {
+            for e in contents {
                 entries.push((e.into(), None));
             }
         }
@@ -196,28 +292,38 @@ fn list_dir_contents(
         if path.len() > 1 && path.last() == Some(&b'/') {
             path.pop();
         }
-        app.out.write(path).write(b":\n");
+        let mut quote_buf = Vec::new();
+        let header = quote::quote_dir_name(path, app, &mut quote_buf);
+        app.out.write(header).write(b":\n");
     }
 
     if app.needs_details {
         for e in &mut entries {
-            let status = if app.follow_symlinks == cli::FollowSymlinks::Always {
-                syscalls::fstatat(dir.raw_fd(), e.name())
-            } else {
-                syscalls::lstatat(dir.raw_fd(), e.name())
-            }
-            .map(|status| app.convert_status(status));
+            let status = app.stat(
+                dir.raw_fd(),
+                e.name(),
+                app.follow_symlinks == cli::FollowSymlinks::Always,
+            );
             match status {
                 Ok(s) => e.1 = Some(s),
                 Err(err) => {
-                    access_error(&e.name(), err);
+                    access_error(&entry_path(path, e.name()), err);
                 }
             }
         }
     }
 
+    if app.group_directories_first && !app.needs_details {
+        let follow_symlinks = app.follow_symlinks == cli::FollowSymlinks::Always;
+        resolve_d_types(&mut entries, dir, follow_symlinks);
+    }
+
     sort_entries(&mut entries, app);
 
+    if app.display_mode == DisplayMode::Long || app.display_size_in_blocks {
+        print_total_blocks(&entries, app);
+    }
+
     match app.display_mode {
         DisplayMode::Grid(width) => write_grid(&entries, dir, app, width),
         DisplayMode::Long => write_details(&entries, dir, app),
@@ -278,17 +384,63 @@ fn list_dir_contents(
 
 #[inline(never)]
 fn access_error(item: &[u8], error: Error) {
+    let item = item.strip_suffix(b"\0").unwrap_or(item);
     let mut out = crate::output::OutputBuffer::to_fd(2);
-    out.write(&b"Unable to access '"[..])
+    out.write(&b"cannot access '"[..])
         .write(item)
-        .write(&b"': OS Error "[..]);
-    error.0.write(&mut out);
+        .write(&b"': "[..]);
+    match error_message(&error) {
+        Some(message) => {
+            out.write(message.as_bytes());
+        }
+        None => {
+            out.write(&b"OS Error "[..]);
+            error.0.write(&mut out);
+        }
+    }
     out.push(b'\n');
 }
 
+// The glibc messages for the errors listing a file is likely to run into
+fn error_message(error: &Error) -> Option<&'static str> {
+    Some(match error.0 {
+        libc::EPERM => "Operation not permitted",
+        libc::ENOENT => "No such file or directory",
+        libc::EIO => "Input/output error",
+        libc::ENXIO => "No such device or address",
+        libc::EBADF => "Bad file descriptor",
+        libc::ENOMEM => "Cannot allocate memory",
+        libc::EACCES => "Permission denied",
+        libc::ENODEV => "No such device",
+        libc::ENOTDIR => "Not a directory",
+        libc::EMFILE => "Too many open files",
+        libc::ENAMETOOLONG => "File name too long",
+        libc::ELOOP => "Too many levels of symbolic links",
+        libc::EOVERFLOW => "Value too large for defined data type",
+        libc::ESTALE => "Stale file handle",
+        _ => return None,
+    })
+}
+
+// Like GNU ls, entries are named by their path in diagnostics, unless they're in .
+fn entry_path(path: &[u8], name: CStr) -> Vec<u8> {
+    let path = path.strip_suffix(b"\0").unwrap_or(path);
+    let mut full = Vec::new();
+    if path != b"." {
+        full.extend_from_slice(path);
+        if path.last() != Some(&b'/') {
+            full.push(b'/');
+        }
+    }
+    full.extend_from_slice(name.as_bytes());
+    full
+}
+
 #[derive(Default, Clone)]
 pub struct Status {
     pub device: libc::dev_t,
+    // The device a character or block special file refers to
+    pub rdev: libc::dev_t,
     pub links: libc::nlink_t,
     pub mode: libc::mode_t,
     pub size: libc::off_t,
@@ -296,6 +448,10 @@ pub struct Status {
     pub block_size: libc::blksize_t,
     pub uid: libc::uid_t,
     pub gid: libc::gid_t,
+    // Both are -1 when the filesystem doesn't know the birth time
     pub time: libc::time_t,
+    pub time_nsec: libc::c_long,
+    // What -l prints: mtime, ctime and atime with --all-times, otherwise the time above
+    pub shown_times: [(libc::time_t, libc::c_long); 3],
     pub inode: libc::ino_t,
 }
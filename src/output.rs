@@ -1,18 +1,24 @@
 use crate::{
-    cli::App,
-    directory::{DirEntry, DirEntryExt},
-    utils::Buffer,
+    cli::{App, FollowSymlinks, SortDirection},
+    directory::{link_target_suffix, DirEntry, DirEntryExt},
+    quote::{pad_unquoted_names, quote_file_name},
+    utils::{getxattr, listxattr, Buffer},
     Status, Style,
 };
 use alloc::vec::Vec;
-use veneer::{fs::Directory, syscalls, CStr};
+use veneer::{
+    fs::{DType, Directory},
+    syscalls, CStr,
+};
 
-use libc::{S_IRGRP, S_IROTH, S_IRUSR, S_IWGRP, S_IWOTH, S_IWUSR, S_IXGRP, S_IXOTH, S_IXUSR};
+use libc::{
+    S_IRGRP, S_IROTH, S_IRUSR, S_ISGID, S_ISUID, S_ISVTX, S_IWGRP, S_IWOTH, S_IWUSR, S_IXGRP,
+    S_IXOTH, S_IXUSR,
+};
 use unicode_width::UnicodeWidthStr;
 
-const BINARY_UNITS_PER_LARGER_UNIT: u64 = 1024;
-const DECIMAL_UNITS_PER_LARGER_UNIT: u64 = 1000;
-static SIZE_UNITS: [u8; 9] = [b'B', b'k', b'M', b'G', b'T', b'P', b'E', b'Z', b'Y'];
+// st_blocks is counted in 512-byte units, whatever the block size of the filesystem is
+const STAT_BLOCK_SIZE: u64 = 512;
 
 #[macro_export]
 macro_rules! print {
@@ -23,47 +29,153 @@ macro_rules! print {
     }};
 }
 
-fn print_rwx(app: &mut App, mode: u32, read_mask: u32, write_mask: u32, execute_mask: u32) {
-    use Style::*;
-
+// The special bit is setuid, setgid or sticky, which replaces the x like in GNU ls: lowercase
+// when the file is also executable, uppercase when it isn't
+fn print_rwx(
+    app: &mut App,
+    mode: u32,
+    read_mask: u32,
+    write_mask: u32,
+    execute_mask: u32,
+    (special_mask, special, special_style): (u32, u8, Style),
+) {
     if mode & read_mask > 0 {
-        app.out.style(YellowBold).push(b'r');
+        app.out.style(Style::YELLOW_BOLD).push(b'r');
     } else {
-        app.out.style(Gray).push(b'-');
+        app.out.style(Style::GRAY).push(b'-');
     }
 
     if mode & write_mask > 0 {
-        app.out.style(RedBold).push(b'w');
+        app.out.style(Style::RED_BOLD).push(b'w');
     } else {
-        app.out.style(Gray).push(b'-');
+        app.out.style(Style::GRAY).push(b'-');
     }
 
-    if mode & execute_mask > 0 {
-        app.out.style(GreenBold).push(b'x');
+    if mode & special_mask > 0 {
+        let special = if mode & execute_mask > 0 {
+            special
+        } else {
+            special.to_ascii_uppercase()
+        };
+        app.out.style(special_style).push(special);
+    } else if mode & execute_mask > 0 {
+        app.out.style(Style::GREEN_BOLD).push(b'x');
     } else {
-        app.out.style(Gray).push(b'-');
+        app.out.style(Style::GRAY).push(b'-');
     }
 }
 
-pub fn write_details(entries: &[(DirEntry, Option<Status>)], dir: &Directory, app: &mut App) {
-    use Style::*;
+fn device_numbers(status: &Status) -> Option<(u64, u64)> {
+    match status.mode & libc::S_IFMT {
+        libc::S_IFCHR | libc::S_IFBLK => Some(unsafe {
+            (
+                libc::major(status.rdev) as u64,
+                libc::minor(status.rdev) as u64,
+            )
+        }),
+        _ => None,
+    }
+}
+
+// Like GNU ls, + marks a file with an ACL beyond its permission bits and . one with only an SELinux
+// context. With -@, @ marks files with user extended attributes.
+fn xattr_indicator(dir: &Directory, name: CStr, list: &mut Vec<u8>, app: &App) -> u8 {
+    let follow_symlinks = app.follow_symlinks == FollowSymlinks::Always;
+    if listxattr(dir.raw_fd(), name, list, follow_symlinks).is_err() {
+        return b' ';
+    }
+    let (mut selinux, mut user) = (false, false);
+    for attribute in list.split(|&b| b == 0) {
+        match attribute {
+            // An ACL with only the owner, group and other entries is just the permission bits
+            b"system.posix_acl_access" => {
+                let acl = b"system.posix_acl_access\0";
+                if getxattr(dir.raw_fd(), name, acl, &mut [], follow_symlinks)
+                    .is_ok_and(|len| len > 4 + 3 * 8)
+                {
+                    return b'+';
+                }
+            }
+            b"system.posix_acl_default" => return b'+',
+            b"security.selinux" => selinux = true,
+            _ => user |= attribute.starts_with(b"user."),
+        }
+    }
+    if user && app.show_xattr_indicator {
+        b'@'
+    } else if selinux {
+        b'.'
+    } else {
+        b' '
+    }
+}
+
+// The SELinux context of each entry for -Z, or ? when a file doesn't have one. Like GNU ls, this is
+// the context of what a symlink points to under -L.
+fn security_contexts(
+    entries: &[(DirEntry, Option<Status>)],
+    dir: &Directory,
+    app: &App,
+) -> (Vec<Vec<u8>>, usize) {
+    if !app.print_context {
+        return (Vec::new(), 0);
+    }
+    let attribute = b"security.selinux\0";
+    let follow_symlinks = app.follow_symlinks == FollowSymlinks::Always;
+    let mut buf = alloc::vec![0; 256];
+    let contexts: Vec<Vec<u8>> = entries
+        .iter()
+        .map(|e| {
+            if e.1.is_none() {
+                return b"?".to_vec();
+            }
+            let get =
+                |buf: &mut [u8]| getxattr(dir.raw_fd(), e.name(), attribute, buf, follow_symlinks);
+            let mut len = get(&mut buf);
+            // Too long for the buffer, so ask how long it is
+            if let Err(crate::Error(libc::ERANGE)) = len {
+                len = get(&mut []).and_then(|size| {
+                    buf.resize(size, 0);
+                    get(&mut buf)
+                });
+            }
+            let mut context = &buf[..len.unwrap_or(0)];
+            if let [rest @ .., 0] = context {
+                context = rest;
+            }
+            if context.is_empty() {
+                b"?".to_vec()
+            } else {
+                context.to_vec()
+            }
+        })
+        .collect();
+    let width = contexts.iter().map(Vec::len).max().unwrap_or(0);
+    (contexts, width)
+}
 
+pub fn write_details(entries: &[(DirEntry, Option<Status>)], dir: &Directory, app: &mut App) {
     let mut longest_name_len = 1;
     let mut longest_group_len = 1;
     let mut largest_size = 0;
+    let mut major_len = 0;
+    let mut minor_len = 0;
     let mut largest_links = 0;
-    let mut blocks = 0;
     let mut inode_len = 0;
     let mut blocks_len = 0;
+    let mut time_widths = [0; 3];
+    let time_columns = if app.show_all_times { 3 } else { 1 };
+    let mut buf = Buffer::new();
 
-    let size_units_per_larger_unit = if app.use_si_size_units {
-        DECIMAL_UNITS_PER_LARGER_UNIT
-    } else {
-        BINARY_UNITS_PER_LARGER_UNIT
-    };
+    let current_time = syscalls::gettimeofday().unwrap().tv_sec;
+    let mut time_buf = Vec::new();
+
+    let mut size_buf = Vec::new();
+    let (block_size, file_block_size) = (app.block_size, app.file_block_size);
 
+    // Human-readable sizes aren't wider for larger numbers, so each one is formatted to measure it
     for status in entries.iter().filter_map(|e| e.1.as_ref()) {
-        if app.print_owner {
+        if app.print_owner || app.print_author {
             longest_name_len = longest_name_len.max(app.getpwuid(status.uid).len());
         }
 
@@ -71,76 +183,180 @@ pub fn write_details(entries: &[(DirEntry, Option<Status>)], dir: &Directory, ap
             longest_group_len = longest_group_len.max(app.getgrgid(status.gid).len());
         }
 
-        largest_size = if app.human_readable_sizes {
-            // extra character is for the unit
-            largest_size.max(
-                convert_to_human_readable_size(status.size as u64, size_units_per_larger_unit).0
-                    as usize
-                    + 1,
-            )
+        if let Some((major, minor)) = device_numbers(status) {
+            major_len = major_len.max(buf.format(major).len());
+            minor_len = minor_len.max(buf.format(minor).len());
         } else {
-            largest_size.max(status.size as usize)
-        };
+            file_block_size.format(status.size as u64, 1, &mut size_buf);
+            largest_size = largest_size.max(len_utf8(&size_buf));
+        }
 
         largest_links = largest_links.max(status.links as usize);
         inode_len = inode_len.max(status.inode as usize);
-        blocks_len = blocks_len.max(status.blocks as usize);
-        blocks += status.blocks * status.block_size / 8192;
-    }
+        if app.display_size_in_blocks {
+            block_size.format(status.blocks as u64, STAT_BLOCK_SIZE, &mut size_buf);
+            blocks_len = blocks_len.max(len_utf8(&size_buf));
+        }
 
-    print!(app, "total ", blocks, "\n");
+        for (&(time, nsec), width) in status.shown_times[..time_columns]
+            .iter()
+            .zip(&mut time_widths)
+        {
+            // Like GNU ls, a missing time is padded to the width of a real one
+            if nsec < 0 {
+                format_time(current_time, 0, current_time, app, &mut time_buf);
+            } else {
+                format_time(time, nsec, current_time, app, &mut time_buf);
+            }
+            *width = (*width).max(len_utf8(&time_buf));
+        }
+    }
 
-    let mut buf = Buffer::new();
-    largest_size = buf.format(largest_size as u64).len();
+    // Device rows print "major, minor" in the size column, so it has to fit those too
+    let device_len = if major_len > 0 {
+        major_len + 2 + minor_len
+    } else {
+        0
+    };
+    largest_size = largest_size.max(device_len);
     largest_links = buf.format(largest_links as u64).len();
     inode_len = buf.format(inode_len as u64).len();
-    blocks_len = buf.format(blocks_len as u64).len();
 
-    let current_time = syscalls::gettimeofday().unwrap().tv_sec;
-    let one_year = 365 * 24 * 60 * 60;
+    let (contexts, context_len) = security_contexts(entries, dir, app);
+    let mut xattr_list = Vec::new();
+    let indicators: Vec<u8> = entries
+        .iter()
+        .map(|e| match e.1 {
+            Some(_) => xattr_indicator(dir, e.name(), &mut xattr_list, app),
+            None => b' ',
+        })
+        .collect();
+    // The indicator column is only there when some file needs it
+    let show_indicators = indicators.iter().any(|&i| i != b' ');
+
+    let mut quote_buf = Vec::new();
+    let pad_names = pad_unquoted_names(app)
+        && entries
+            .iter()
+            .any(|e| quote_file_name(e.name().as_bytes(), app, &mut quote_buf).1);
 
-    for direntry in entries {
+    for (i, (direntry, &indicator)) in entries.iter().zip(&indicators).enumerate() {
         let e = &direntry.0;
-        let status = direntry.1.clone().unwrap_or_default();
-        let mode = status.mode;
+        // Like GNU ls, a file that couldn't be stat'd is listed with ? for everything unknown
+        let status = direntry.1.as_ref();
+        let mode = status.map_or_else(|| d_type_mode(e.d_type), |s| s.mode);
 
         if app.print_inode {
-            app.out
-                .style(Magenta)
-                .align_right(status.inode, inode_len)
-                .push(b' ');
+            app.out.style(Style::MAGENTA);
+            match status {
+                Some(status) => app.out.align_right(status.inode, inode_len),
+                None => app.out.align_right_bytes(b"?", inode_len),
+            }
+            .push(b' ');
         }
 
         if app.display_size_in_blocks {
+            match status {
+                Some(status) => {
+                    block_size.format(status.blocks as u64, STAT_BLOCK_SIZE, &mut size_buf)
+                }
+                None => {
+                    size_buf.clear();
+                    size_buf.push(b'?');
+                }
+            }
             app.out
-                .style(White)
-                .align_right(status.blocks as u64, blocks_len)
+                .style(Style::WHITE)
+                .align_right_bytes(&size_buf, blocks_len)
                 .push(b' ');
         }
 
         print!(
             app,
             match mode & libc::S_IFMT {
-                libc::S_IFDIR => (BlueBold, "d"),
-                libc::S_IFLNK => (Cyan, "l"),
-                _ => (White, "-"),
+                libc::S_IFREG => (Style::WHITE, "-"),
+                libc::S_IFDIR => (Style::BLUE_BOLD, "d"),
+                libc::S_IFLNK => (Style::CYAN, "l"),
+                libc::S_IFCHR => (Style::YELLOW_BOLD, "c"),
+                libc::S_IFBLK => (Style::YELLOW_BOLD, "b"),
+                libc::S_IFIFO => (Style::YELLOW_BOLD, "p"),
+                libc::S_IFSOCK => (Style::MAGENTA_BOLD, "s"),
+                _ => (Style::RED_BOLD, "?"),
             }
         );
 
-        print_rwx(app, mode, S_IRUSR, S_IWUSR, S_IXUSR);
-        print_rwx(app, mode, S_IRGRP, S_IWGRP, S_IXGRP);
-        print_rwx(app, mode, S_IROTH, S_IWOTH, S_IXOTH);
+        let status = match status {
+            Some(status) => status,
+            None => {
+                print!(app, Style::GRAY, "?????????");
+                if show_indicators {
+                    app.out.push(indicator);
+                }
+                app.out
+                    .push(b' ')
+                    .style(Style::WHITE)
+                    .align_right_bytes(b"?", largest_links);
+                if app.print_owner {
+                    app.out.push(b' ').align_left(b"?", longest_name_len);
+                }
+                if app.print_group {
+                    app.out.push(b' ').align_left(b"?", longest_group_len);
+                }
+                if app.print_author {
+                    app.out.push(b' ').align_left(b"?", longest_name_len);
+                }
+                if let Some(context) = contexts.get(i) {
+                    app.out.push(b' ').align_left(context, context_len);
+                }
+                app.out.push(b' ').align_right_bytes(b"?", largest_size);
+                for &width in &time_widths[..time_columns] {
+                    app.out.push(b' ').align_right_bytes(b"?", width);
+                }
+                app.out.push(b' ');
+                print_name(direntry, dir, mode, pad_names, &mut quote_buf, app);
+                print!(app, Style::RESET, app.end_of_line);
+                continue;
+            }
+        };
+
+        print_rwx(
+            app,
+            mode,
+            S_IRUSR,
+            S_IWUSR,
+            S_IXUSR,
+            (S_ISUID, b's', Style::MAGENTA_BOLD),
+        );
+        print_rwx(
+            app,
+            mode,
+            S_IRGRP,
+            S_IWGRP,
+            S_IXGRP,
+            (S_ISGID, b's', Style::CYAN_BOLD),
+        );
+        print_rwx(
+            app,
+            mode,
+            S_IROTH,
+            S_IWOTH,
+            S_IXOTH,
+            (S_ISVTX, b't', Style::BLUE_BOLD),
+        );
+        if show_indicators {
+            app.out.style(Style::WHITE).push(indicator);
+        }
 
         app.out
             .push(b' ')
-            .style(White)
+            .style(Style::WHITE)
             .align_right(status.links, largest_links);
 
         if app.print_owner {
             let name = app.getpwuid(status.uid);
             app.out
                 .push(b' ')
-                .style(YellowBold)
+                .style(Style::YELLOW_BOLD)
                 .align_left(name, longest_name_len);
         }
 
@@ -148,86 +364,135 @@ pub fn write_details(entries: &[(DirEntry, Option<Status>)], dir: &Directory, ap
             let group = app.getgrgid(status.gid);
             app.out
                 .push(b' ')
-                .style(YellowBold)
+                .style(Style::YELLOW_BOLD)
                 .align_left(group, longest_group_len);
         }
 
-        app.out.push(b' ').style(GreenBold);
-
-        if app.human_readable_sizes {
-            app.out.align_right_human_readable_size(
-                status.size as u64,
-                largest_size,
-                size_units_per_larger_unit,
-            );
-        } else {
-            app.out.align_right(status.size as u64, largest_size);
+        // Linux has no separate author, so like GNU ls this is the owner again
+        if app.print_author {
+            let name = app.getpwuid(status.uid);
+            app.out
+                .push(b' ')
+                .style(Style::YELLOW_BOLD)
+                .align_left(name, longest_name_len);
         }
 
-        let localtime = app.convert_to_localtime(status.time);
-
-        print!(app, " ", Blue, month_abbr(localtime.month), " ");
-
-        let day = localtime.day_of_month;
-        print!(app, (day < 10).map(" "), day, " ");
+        if let Some(context) = contexts.get(i) {
+            app.out
+                .push(b' ')
+                .style(Style::WHITE)
+                .align_left(context, context_len);
+        }
 
-        if current_time - status.time < one_year / 2 {
-            let hour = localtime.hour;
-            print!(app, (hour < 10).map("0"), hour, ":");
+        app.out.push(b' ').style(Style::GREEN_BOLD);
 
-            let minute = localtime.minute;
-            print!(app, (minute < 10).map("0"), minute);
+        if let Some((major, minor)) = device_numbers(status) {
+            app.out
+                .align_right(major, largest_size - 2 - minor_len)
+                .write(b", ")
+                .align_right(minor, minor_len);
         } else {
-            print!(app, " ", localtime.year + 1900);
+            file_block_size.format(status.size as u64, 1, &mut size_buf);
+            app.out.align_right_bytes(&size_buf, largest_size);
         }
 
+        // Times can be formatted differently depending on their age, so they're padded to line up
+        for (&(time, nsec), &width) in status.shown_times[..time_columns].iter().zip(&time_widths) {
+            format_time(time, nsec, current_time, app, &mut time_buf);
+            if nsec < 0 || app.relative_time {
+                print!(app, " ", Style::BLUE);
+                app.out.align_right_bytes(&time_buf, width);
+            } else {
+                print!(app, " ", Style::BLUE, &time_buf[..]);
+                for _ in len_utf8(&time_buf)..width {
+                    app.out.push(b' ');
+                }
+            }
+        }
         app.out.push(b' ');
 
-        let (mut style, suffix) = direntry.style(dir, app);
-        // FIXME: This is a hack to get red-colored broken symlinks in -l output.
-        // This logic is at completely the wrong place, and it's setting the style to RedBold, not
-        // BrokenLink.
-        if (mode & libc::S_IFMT) == libc::S_IFLNK
-            && app.color == crate::cli::Color::Always
-            && syscalls::faccessat(dir.raw_fd(), e.name, libc::F_OK).is_err()
-        {
-            style = RedBold;
-        }
-        print!(app, style, e.name, suffix.map(|s| (White, s)));
+        print_name(direntry, dir, mode, pad_names, &mut quote_buf, app);
 
         if (mode & libc::S_IFMT) == libc::S_IFLNK {
             let mut buf = [0u8; 1024];
             if let Ok(linked_to) = syscalls::readlinkat(dir.raw_fd(), e.name, &mut buf) {
-                print!(app, Gray, " -> ", White, linked_to);
+                let linked_to = quote_file_name(linked_to, app, &mut quote_buf).0;
+                let style = match app.ls_colors.missing {
+                    Some(missing)
+                        if app.color == crate::cli::Color::Always
+                            && syscalls::faccessat(dir.raw_fd(), e.name, libc::F_OK).is_err() =>
+                    {
+                        missing
+                    }
+                    _ => Style::WHITE,
+                };
+                print!(app, Style::GRAY, " -> ", style, linked_to);
+                let suffix = link_target_suffix(dir, e.name, app);
+                print!(app, suffix.map(|s| (Style::WHITE, s)));
             }
         }
 
-        print!(app, Reset, "\n");
+        print!(app, Style::RESET, app.end_of_line);
     }
 }
 
-fn print_total_blocks(entries: &[(DirEntry, Option<Status>)], app: &mut App) {
+// In long listings a symlink's indicator goes after its target instead, like GNU ls
+fn print_name(
+    entry: &(DirEntry, Option<Status>),
+    dir: &Directory,
+    mode: u32,
+    pad_names: bool,
+    quote_buf: &mut Vec<u8>,
+    app: &mut App,
+) {
+    let (style, mut suffix) = entry.style(dir, app);
+    if mode & libc::S_IFMT == libc::S_IFLNK {
+        suffix = None;
+    }
+    let (name, quoted) = quote_file_name(entry.name().as_bytes(), app, quote_buf);
     print!(
         app,
-        "total ",
-        entries
-            .iter()
-            .filter_map(|(_, s)| s.as_ref())
-            .map(|status| status.blocks)
-            .sum::<i64>() as u64,
-        "\n"
+        style,
+        (pad_names && !quoted).map(" "),
+        name,
+        suffix.map(|s| (Style::WHITE, s))
     );
 }
 
-fn convert_to_human_readable_size(mut size: u64, size_units_per_larger_unit: u64) -> (u64, u8) {
-    let mut unit_index = 0;
-
-    while size >= size_units_per_larger_unit && unit_index < SIZE_UNITS.len() {
-        size = size.div_ceil(size_units_per_larger_unit);
-        unit_index += 1;
+// The mode bits for the type of a file that couldn't be stat'd, so its type can still be shown
+fn d_type_mode(d_type: DType) -> u32 {
+    match d_type {
+        DType::REG => libc::S_IFREG,
+        DType::DIR => libc::S_IFDIR,
+        DType::LNK => libc::S_IFLNK,
+        DType::CHR => libc::S_IFCHR,
+        DType::BLK => libc::S_IFBLK,
+        DType::FIFO => libc::S_IFIFO,
+        DType::SOCK => libc::S_IFSOCK,
+        _ => 0,
     }
+}
 
-    (size, SIZE_UNITS[unit_index])
+// The total line of a directory listing, with st_blocks scaled to the block size like the -s column.
+// GNU ls leaves it out for files named on the command line.
+pub fn print_total_blocks(entries: &[(DirEntry, Option<Status>)], app: &mut App) {
+    let blocks = entries.iter().map(DirEntryExt::blocks).sum();
+    let mut buf = Vec::new();
+    app.block_size.format(blocks, STAT_BLOCK_SIZE, &mut buf);
+    print!(app, "total ", &buf[..], app.end_of_line);
+}
+
+// The width of the -s column. With -h a smaller count can be the wider one, so all are formatted.
+fn blocks_width(entries: &[(DirEntry, Option<Status>)], app: &App) -> usize {
+    let mut buf = Vec::new();
+    entries
+        .iter()
+        .map(|e| {
+            app.block_size.format(e.blocks(), STAT_BLOCK_SIZE, &mut buf);
+            len_utf8(&buf)
+        })
+        .max()
+        .unwrap_or(0)
 }
 
 pub struct LayoutCursor {
@@ -243,12 +508,6 @@ pub fn write_grid(
     app: &mut App,
     terminal_width: usize,
 ) {
-    use Style::*;
-
-    if app.display_size_in_blocks {
-        print_total_blocks(entries, app);
-    }
-
     if entries.is_empty() {
         return;
     }
@@ -261,17 +520,13 @@ pub fn write_grid(
     };
 
     let blocks_len = if app.display_size_in_blocks {
-        let blocks = entries
-            .iter()
-            .filter_map(|(_, s)| s.as_ref())
-            .map(|status| status.blocks)
-            .max()
-            .unwrap_or(0);
-        Buffer::new().format(blocks as u64).len()
+        blocks_width(entries, app)
     } else {
         0
     };
 
+    let (contexts, context_len) = security_contexts(entries, dir, app);
+
     // We want to determine the maximum number of columns we can use to lay out these entries.
     // So we simulate arrangingi the entries in every possible layout at the same time. Notionally,
     // we keep a Vec of column widths (widest name in each column) for every number of columns, and
@@ -283,12 +538,22 @@ pub fn write_grid(
     let mut lengths = Vec::with_capacity(entries.len());
     let mut styles = Vec::with_capacity(entries.len());
 
-    let max_possible_columns = core::cmp::min(terminal_width / 3, entries.len());
+    let mut quote_buf = Vec::new();
+    let pad_names = pad_unquoted_names(app)
+        && entries
+            .iter()
+            .any(|e| quote_file_name(e.name().as_bytes(), app, &mut quote_buf).1);
+    let mut blocks_buf = Vec::new();
+
+    let max_possible_columns = core::cmp::min(terminal_width / 3, entries.len()).max(1);
 
     let mut layouts = Vec::with_capacity(sum_to(max_possible_columns) - 1);
     let mut cursors = Vec::with_capacity(max_possible_columns - 1);
 
-    for i in 2..=max_possible_columns {
+    // Filling rows first is laid out separately, once we know every entry's length
+    let vertical = app.grid_sort_direction == SortDirection::Vertical;
+
+    for i in (2..=max_possible_columns).filter(|_| vertical) {
         layouts.extend(core::iter::repeat(0).take(i));
         // current position, increments left until we move to the next column
         let rows = (entries.len() + i - 1) / i;
@@ -305,8 +570,21 @@ pub fn write_grid(
 
     for entry in entries {
         let style = entry.style(dir, app);
-        let len =
-            len_utf8(entry.name().as_bytes()) + style.1.is_some() as usize + inode_len + blocks_len;
+        let (name, quoted) = quote_file_name(entry.name().as_bytes(), app, &mut quote_buf);
+        let len = len_utf8(name)
+            + (pad_names && !quoted) as usize
+            + style.1.is_some() as usize
+            + if app.print_inode { inode_len + 1 } else { 0 }
+            + if app.display_size_in_blocks {
+                blocks_len + 1
+            } else {
+                0
+            }
+            + if app.print_context {
+                context_len + 1
+            } else {
+                0
+            };
         lengths.push(len);
         styles.push(style);
 
@@ -331,24 +609,40 @@ pub fn write_grid(
         }
     }
 
-    let rows = cursors.last().map(|c| c.rows).unwrap_or(entries.len());
+    let (rows, widths) = if vertical {
+        let rows = cursors.last().map(|c| c.rows).unwrap_or(entries.len());
 
-    let mut widths = Vec::new();
-    widths.extend(
-        lengths
-            .chunks(rows)
-            .map(|column| column.iter().max().copied().unwrap_or(1) + 2),
-    );
-    if let Some(width) = widths.last_mut() {
-        *width -= 2;
-    }
+        let mut widths = Vec::new();
+        widths.extend(
+            lengths
+                .chunks(rows)
+                .map(|column| column.iter().max().copied().unwrap_or(1) + 2),
+        );
+        if let Some(width) = widths.last_mut() {
+            *width -= 2;
+        }
+        (rows, widths)
+    } else {
+        let widths = horizontal_column_widths(&lengths, terminal_width);
+        let rows = entries.len().div_ceil(widths.len());
+        (rows, widths)
+    };
+
+    let index = |r: usize, c: usize| {
+        if vertical {
+            c * rows + r
+        } else {
+            r * widths.len() + c
+        }
+    };
 
     for r in 0..rows {
+        let mut position = 0;
         for (c, width) in widths.iter().enumerate() {
             let (e, name_len, (style, suffix)) = match (
-                entries.get(c * rows + r),
-                lengths.get(c * rows + r),
-                styles.get(c * rows + r),
+                entries.get(index(r, c)),
+                lengths.get(index(r, c)),
+                styles.get(index(r, c)),
             ) {
                 (Some(e), Some(name_len), Some(style)) => (e, name_len, style),
                 _ => continue,
@@ -356,66 +650,113 @@ pub fn write_grid(
 
             if app.print_inode {
                 app.out
-                    .style(Magenta)
+                    .style(Style::MAGENTA)
                     .align_right(e.inode(), inode_len)
                     .push(b' ');
             }
 
             if app.display_size_in_blocks {
+                app.block_size
+                    .format(e.blocks(), STAT_BLOCK_SIZE, &mut blocks_buf);
                 app.out
-                    .style(White)
-                    .align_right(e.blocks(), blocks_len)
+                    .style(Style::WHITE)
+                    .align_right_bytes(&blocks_buf, blocks_len)
                     .push(b' ');
             }
 
+            if let Some(context) = contexts.get(index(r, c)) {
+                app.out
+                    .style(Style::WHITE)
+                    .align_right_bytes(context, context_len)
+                    .push(b' ');
+            }
+
+            let (name, quoted) = quote_file_name(e.name().as_bytes(), app, &mut quote_buf);
             app.out.style(*style);
-            print!(app, e.name(), suffix.map(|s| (White, s)));
+            print!(
+                app,
+                (pad_names && !quoted).map(" "),
+                name,
+                suffix.map(|s| (Style::WHITE, s))
+            );
 
-            for _ in 0..(width - name_len) {
-                app.out.push(b' ');
+            // Don't leave trailing whitespace after the last name in a row
+            if c + 1 < widths.len() && index(r, c + 1) < entries.len() {
+                indent(app, position + name_len, position + width);
             }
+            position += width;
         }
-        app.out.style(Reset).push(b'\n');
+        app.out.style(Style::RESET).push(app.end_of_line);
     }
 
     app.out.flush();
 }
 
-pub fn write_stream(entries: &[(DirEntry, Option<Status>)], dir: &Directory, app: &mut App) {
-    if app.display_size_in_blocks {
-        print_total_blocks(entries, app);
+// Pads from one column to another like GNU ls, with a tab whenever it reaches the next tab stop
+fn indent(app: &mut App, mut from: usize, to: usize) {
+    let tab_size = app.tab_size;
+    while from < to {
+        if tab_size != 0 && to / tab_size > (from + 1) / tab_size {
+            app.out.push(b'\t');
+            from += tab_size - from % tab_size;
+        } else {
+            app.out.push(b' ');
+            from += 1;
+        }
     }
+}
 
-    for e in entries.iter().take(entries.len() - 1) {
+// This follows calculate_columns in GNU ls: every column is at least 3 wide, all but the last are
+// followed by 2 spaces, and the line must end up strictly shorter than the terminal.
+fn horizontal_column_widths(lengths: &[usize], terminal_width: usize) -> Vec<usize> {
+    let max_possible_columns = core::cmp::max(terminal_width / 3, 1).min(lengths.len());
+
+    let mut widths = Vec::with_capacity(max_possible_columns);
+    for columns in (1..=max_possible_columns).rev() {
+        widths.clear();
+        widths.resize(columns, 3);
+        for (i, len) in lengths.iter().enumerate() {
+            let column = i % columns;
+            let len = if column == columns - 1 { *len } else { len + 2 };
+            widths[column] = widths[column].max(len);
+        }
+        if widths.iter().sum::<usize>() < terminal_width {
+            break;
+        }
+    }
+    widths
+}
+
+pub fn write_stream(entries: &[(DirEntry, Option<Status>)], dir: &Directory, app: &mut App) {
+    let contexts = security_contexts(entries, dir, app).0;
+    let mut quote_buf = Vec::new();
+    let mut blocks_buf = Vec::new();
+    for (i, e) in entries.iter().enumerate() {
         if app.print_inode {
-            print!(app, Style::Magenta, e.inode(), " ");
+            print!(app, Style::MAGENTA, e.inode(), " ");
         }
 
         if app.display_size_in_blocks {
-            print!(app, Style::White, e.blocks(), " ");
+            app.block_size
+                .format(e.blocks(), STAT_BLOCK_SIZE, &mut blocks_buf);
+            print!(app, Style::WHITE, &blocks_buf[..], " ");
+        }
+
+        if let Some(context) = contexts.get(i) {
+            print!(app, Style::WHITE, &context[..], " ");
         }
 
         let (style, suffix) = e.style(dir, app);
-        print!(
-            app,
-            style,
-            e.name(),
-            suffix.map(|s| (Style::White, s)),
-            Style::White,
-            ", "
-        );
-    }
-    if let Some(e) = entries.last() {
-        app.out.write(e.name().as_bytes());
+        let name = quote_file_name(e.name().as_bytes(), app, &mut quote_buf).0;
+        print!(app, style, name, suffix.map(|s| (Style::WHITE, s)));
+        if i + 1 < entries.len() {
+            print!(app, Style::WHITE, ", ");
+        }
     }
-    app.out.push(b'\n');
+    print!(app, Style::RESET, app.end_of_line);
 }
 
 pub fn write_single_column(entries: &[(DirEntry, Option<Status>)], dir: &Directory, app: &mut App) {
-    if app.display_size_in_blocks {
-        print_total_blocks(entries, app);
-    }
-
     let inode_len = if app.print_inode {
         let inode = entries.iter().map(DirEntryExt::inode).max().unwrap_or(0);
         Buffer::new().format(inode).len()
@@ -424,41 +765,54 @@ pub fn write_single_column(entries: &[(DirEntry, Option<Status>)], dir: &Directo
     };
 
     let blocks_len = if app.display_size_in_blocks {
-        let blocks = entries.iter().map(DirEntryExt::blocks).max().unwrap_or(0);
-        Buffer::new().format(blocks).len()
+        blocks_width(entries, app)
     } else {
         0
     };
 
-    for e in entries {
+    let (contexts, context_len) = security_contexts(entries, dir, app);
+
+    let mut quote_buf = Vec::new();
+    let mut blocks_buf = Vec::new();
+    for (i, e) in entries.iter().enumerate() {
         if app.print_inode {
             app.out
-                .style(Style::Magenta)
+                .style(Style::MAGENTA)
                 .align_right(e.inode(), inode_len)
                 .push(b' ');
         }
 
         if app.display_size_in_blocks {
+            app.block_size
+                .format(e.blocks(), STAT_BLOCK_SIZE, &mut blocks_buf);
             app.out
-                .style(Style::White)
-                .align_right(e.blocks(), blocks_len)
+                .style(Style::WHITE)
+                .align_right_bytes(&blocks_buf, blocks_len)
+                .push(b' ');
+        }
+
+        if let Some(context) = contexts.get(i) {
+            app.out
+                .style(Style::WHITE)
+                .align_right_bytes(context, context_len)
                 .push(b' ');
         }
 
         let (style, suffix) = e.style(dir, app);
+        let name = quote_file_name(e.name().as_bytes(), app, &mut quote_buf).0;
         print!(
             app,
             style,
-            e.name(),
-            suffix.map(|s| (Style::White, s)),
-            Style::Reset,
-            "\n"
+            name,
+            suffix.map(|s| (Style::WHITE, s)),
+            Style::RESET,
+            app.end_of_line
         );
     }
 }
 
 #[inline(never)]
-fn len_utf8(bytes: &[u8]) -> usize {
+pub fn len_utf8(bytes: &[u8]) -> usize {
     if bytes.iter().all(u8::is_ascii) {
         bytes.len()
     } else {
@@ -511,9 +865,11 @@ impl Writable for u8 {
 
 impl Writable for i64 {
     fn write(&self, out: &mut OutputBuffer) {
-        use core::convert::TryFrom;
+        if *self < 0 {
+            out.push(b'-');
+        }
         let mut buf = Buffer::new();
-        out.write(buf.format(u64::try_from(*self).unwrap()));
+        out.write(buf.format(self.unsigned_abs()));
     }
 }
 
@@ -577,7 +933,7 @@ impl OutputBuffer {
         Self {
             buf: [0u8; 4096],
             buf_used: 0,
-            style: Style::Reset,
+            style: Style::RESET,
             color: true,
             fd,
         }
@@ -640,23 +996,11 @@ impl OutputBuffer {
         self.align_right_bytes(buf.format(value), width)
     }
 
-    pub fn align_right_human_readable_size(
-        &mut self,
-        value: u64,
-        width: usize,
-        size_units_per_larger_unit: u64,
-    ) -> &mut Self {
-        let (size, unit) = convert_to_human_readable_size(value, size_units_per_larger_unit);
-
-        let mut buf = Buffer::new();
-        self.align_right_bytes(buf.format(size), width);
-        self.push(unit);
-        self
-    }
-
+    // Padded to a width on screen, since sizes and times can have multibyte separators
     pub fn align_right_bytes(&mut self, value: &[u8], width: usize) -> &mut Self {
-        if value.len() < width {
-            for _ in 0..width - value.len() {
+        let value_width = len_utf8(value);
+        if value_width < width {
+            for _ in 0..width - value_width {
                 self.push(b' ');
             }
         }
@@ -674,7 +1018,7 @@ impl core::fmt::Write for OutputBuffer {
 
 impl Drop for OutputBuffer {
     fn drop(&mut self) {
-        self.style(Style::Reset);
+        self.style(Style::RESET);
         // Panicking in a Drop is probably a bad idea, so we prefer to be slightly wrong
         // in the case that our index has become invalid
         if let Some(buf) = self.buf.get(..self.buf_used) {
@@ -693,20 +1037,66 @@ fn write_all(bytes: &[u8], fd: i32) {
     }
 }
 
-fn month_abbr(month: libc::c_int) -> &'static [u8] {
-    let month_names = [
-        b"Jan", b"Feb", b"Mar", b"Apr", b"May", b"Jun", b"Jul", b"Aug", b"Sep", b"Oct", b"Nov",
-        b"Dec",
+// Like GNU ls, times in the last six months are recent, and so are shown in more detail
+fn format_time(
+    time: libc::time_t,
+    nsec: libc::c_long,
+    current_time: i64,
+    app: &App,
+    out: &mut Vec<u8>,
+) {
+    let six_months = 31_556_952 / 2;
+    let format = if current_time - six_months < time && time <= current_time {
+        app.recent_time_format
+    } else {
+        app.old_time_format
+    };
+    out.clear();
+    if nsec < 0 {
+        out.push(b'?');
+        return;
+    }
+    if app.relative_time {
+        format_relative_time(current_time - time, out);
+        return;
+    }
+    let mut localtime = app.convert_to_localtime(time);
+    localtime.nanosecond = nsec as u32;
+    crate::time::strftime(format, &localtime, &app.time_locale, out);
+}
+
+// Ages are shown in the largest unit that fits, so they stay short enough to line up
+fn format_relative_time(age: i64, out: &mut Vec<u8>) {
+    const UNITS: [(u64, &str, &str); 6] = [
+        (31_556_952, "year", "years"),
+        (2_629_746, "month", "months"),
+        (86_400, "day", "days"),
+        (3_600, "hour", "hours"),
+        (60, "min", "min"),
+        (1, "sec", "sec"),
     ];
-    month_names.get(month as usize).copied().unwrap_or(b"???")
+    let seconds = age.unsigned_abs();
+    let (size, singular, plural) = UNITS
+        .iter()
+        .copied()
+        .find(|(size, ..)| seconds >= *size)
+        .unwrap_or(UNITS[UNITS.len() - 1]);
+    let count = seconds / size;
+    if age < 0 {
+        out.extend_from_slice(b"in ");
+    }
+    out.extend_from_slice(Buffer::new().format(count));
+    out.push(b' ');
+    out.extend_from_slice(if count == 1 { singular } else { plural }.as_bytes());
+    if age >= 0 {
+        out.extend_from_slice(b" ago");
+    }
 }
 
 // This code was translated almost directly from the implementation in GNU ls
 //
-pub fn vercmp(s1_cstr: CStr, s2_cstr: CStr) -> core::cmp::Ordering {
+pub fn vercmp(s1: &[u8], s2: &[u8]) -> core::cmp::Ordering {
     use core::cmp::Ordering;
-    let s1 = s1_cstr.as_bytes();
-    let s2 = s2_cstr.as_bytes();
     let mut s1_pos: usize = 0;
     let mut s2_pos: usize = 0;
 
@@ -753,6 +1143,111 @@ pub fn vercmp(s1_cstr: CStr, s2_cstr: CStr) -> core::cmp::Ordering {
     Ordering::Equal
 }
 
+// A port of gnulib's filevercmp, which GNU ls uses for -v. Unlike vercmp, this is case-sensitive
+// and compares file suffixes like .tar.gz separately from the rest of the name.
+pub fn filevercmp(a: &[u8], b: &[u8]) -> core::cmp::Ordering {
+    use core::cmp::Ordering;
+
+    // Empty names sort first, then ".", then "..", then other names with a leading "."
+    for special in [&b""[..], b".", b".."] {
+        match (a == special, b == special) {
+            (true, true) => return Ordering::Equal,
+            (true, false) => return Ordering::Less,
+            (false, true) => return Ordering::Greater,
+            (false, false) => {}
+        }
+    }
+    match (a[0] == b'.', b[0] == b'.') {
+        (true, false) => return Ordering::Less,
+        (false, true) => return Ordering::Greater,
+        _ => {}
+    }
+
+    let a_prefix_len = file_prefix_len(a);
+    let b_prefix_len = file_prefix_len(b);
+    let result = verrevcmp(&a[..a_prefix_len], &b[..b_prefix_len]);
+    if result != Ordering::Equal || (a_prefix_len == a.len() && b_prefix_len == b.len()) {
+        result
+    } else {
+        verrevcmp(a, b)
+    }
+}
+
+// The length of the name without a suffix matching (\.[A-Za-z~][A-Za-z0-9~]*)*$
+fn file_prefix_len(s: &[u8]) -> usize {
+    let mut prefix_len = 0;
+    let mut i = 0;
+    while i < s.len() {
+        i += 1;
+        prefix_len = i;
+        while i + 1 < s.len()
+            && s[i] == b'.'
+            && (s[i + 1].is_ascii_alphabetic() || s[i + 1] == b'~')
+        {
+            i += 2;
+            while i < s.len() && (s[i].is_ascii_alphanumeric() || s[i] == b'~') {
+                i += 1;
+            }
+        }
+    }
+    prefix_len
+}
+
+fn verrevcmp(s1: &[u8], s2: &[u8]) -> core::cmp::Ordering {
+    use core::cmp::Ordering;
+
+    // Letters sort before other characters, and ~ before anything, even the end of the name
+    fn order(s: &[u8], pos: usize) -> i32 {
+        match s.get(pos) {
+            None => -1,
+            Some(c) if c.is_ascii_digit() => 0,
+            Some(c) if c.is_ascii_alphabetic() => i32::from(*c),
+            Some(b'~') => -2,
+            Some(c) => i32::from(*c) + 256,
+        }
+    }
+
+    let mut s1_pos = 0;
+    let mut s2_pos = 0;
+    while s1_pos < s1.len() || s2_pos < s2.len() {
+        let mut first_diff = Ordering::Equal;
+        while (s1_pos < s1.len() && !s1.digit_at(s1_pos))
+            || (s2_pos < s2.len() && !s2.digit_at(s2_pos))
+        {
+            let s1_c = order(s1, s1_pos);
+            let s2_c = order(s2, s2_pos);
+            if s1_c != s2_c {
+                return s1_c.cmp(&s2_c);
+            }
+            s1_pos += 1;
+            s2_pos += 1;
+        }
+        while s1.get(s1_pos) == Some(&b'0') {
+            s1_pos += 1;
+        }
+        while s2.get(s2_pos) == Some(&b'0') {
+            s2_pos += 1;
+        }
+        while s1.digit_at(s1_pos) && s2.digit_at(s2_pos) {
+            if first_diff == Ordering::Equal {
+                first_diff = s1[s1_pos].cmp(&s2[s2_pos]);
+            }
+            s1_pos += 1;
+            s2_pos += 1;
+        }
+        if s1.digit_at(s1_pos) {
+            return Ordering::Greater;
+        }
+        if s2.digit_at(s2_pos) {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+    Ordering::Equal
+}
+
 trait SliceExt {
     fn digit_at(&self, index: usize) -> bool;
 }
@@ -0,0 +1,221 @@
+use crate::cli::{App, DisplayMode, QuotingStyle, Suffixes};
+use alloc::vec::Vec;
+
+// This is a port of the parts of gnulib's quotearg that GNU ls uses. Unlike GNU, we do not
+// consult the locale; names are assumed to be UTF-8, and any bytes that are not valid UTF-8 are
+// treated as unprintable.
+
+pub fn quote_file_name<'a>(name: &'a [u8], app: &App, buf: &'a mut Vec<u8>) -> (&'a [u8], bool) {
+    // GNU ls escapes the indicators and, in the escape style, spaces. Oddly, * is only escaped
+    // with --indicator-style=file-type, which never appends it.
+    let escape_too: &[u8] = match (app.quoting_style, app.suffixes) {
+        (QuotingStyle::Escape, Suffixes::All) => b" =>@|",
+        (QuotingStyle::Escape, Suffixes::FileType) => b" *=>@|",
+        (QuotingStyle::Escape, _) => b" ",
+        (_, Suffixes::All) => b"=>@|",
+        (_, Suffixes::FileType) => b"*=>@|",
+        _ => b"",
+    };
+    quote(
+        name,
+        app.quoting_style,
+        app.replace_unprintable_bytes,
+        escape_too,
+        buf,
+    )
+}
+
+pub fn quote_dir_name<'a>(name: &'a [u8], app: &App, buf: &'a mut Vec<u8>) -> &'a [u8] {
+    quote(
+        name,
+        app.quoting_style,
+        app.replace_unprintable_bytes,
+        b":",
+        buf,
+    )
+    .0
+}
+
+// When some names in a listing are wrapped in quotes, GNU ls indents the others by one space so
+// that the names themselves still line up
+pub fn pad_unquoted_names(app: &App) -> bool {
+    matches!(app.display_mode, DisplayMode::Grid(_) | DisplayMode::Long)
+        && matches!(
+            app.quoting_style,
+            QuotingStyle::Shell | QuotingStyle::ShellEscape
+        )
+}
+
+/// Returns the quoted name, and whether it differs from the original
+pub fn quote<'a>(
+    name: &'a [u8],
+    style: QuotingStyle,
+    replace_unprintable: bool,
+    escape_too: &[u8],
+    buf: &'a mut Vec<u8>,
+) -> (&'a [u8], bool) {
+    use QuotingStyle::*;
+
+    let replace_unprintable = replace_unprintable && matches!(style, Literal | Shell | ShellAlways);
+
+    // Names that don't need any quoting are left out of buf, so that we don't copy them
+    buf.clear();
+    match style {
+        Literal => {}
+        Shell | ShellEscape if !needs_shell_quoting(name, style == ShellEscape, escape_too) => {}
+        // Like gnulib, the extra characters only decide whether these styles quote at all
+        Shell => quote_shell(name, false, b"", buf),
+        ShellEscape => quote_shell(name, true, b"", buf),
+        ShellAlways => quote_shell(name, false, escape_too, buf),
+        ShellEscapeAlways => quote_shell(name, true, escape_too, buf),
+        C => quote_c(name, true, escape_too, buf),
+        Escape => quote_c(name, false, escape_too, buf),
+    }
+    let quoted = !buf.is_empty() && buf.as_slice() != name;
+
+    if replace_unprintable {
+        let quoted_name = if buf.is_empty() { name } else { &buf[..] };
+        let mut replaced = Vec::with_capacity(quoted_name.len());
+        for unit in units(quoted_name) {
+            if unit.printable {
+                replaced.extend_from_slice(unit.bytes);
+            } else {
+                replaced.push(b'?');
+            }
+        }
+        *buf = replaced;
+    } else if buf.is_empty() {
+        return (name, false);
+    }
+
+    (buf.as_slice(), quoted)
+}
+
+// A single character of a name; either a valid UTF-8 sequence or one byte that isn't part of one
+struct Unit<'a> {
+    bytes: &'a [u8],
+    printable: bool,
+}
+
+fn units(name: &[u8]) -> impl Iterator<Item = Unit<'_>> {
+    name.utf8_chunks().flat_map(|chunk| {
+        let valid = chunk.valid();
+        let invalid = chunk.invalid();
+        valid
+            .char_indices()
+            .map(move |(i, c)| Unit {
+                bytes: &valid.as_bytes()[i..i + c.len_utf8()],
+                printable: !c.is_control(),
+            })
+            .chain(invalid.chunks(1).map(|b| Unit {
+                bytes: b,
+                printable: false,
+            }))
+    })
+}
+
+fn is_shell_special(b: u8) -> bool {
+    b"\n\r\t !\"$&'()*;<=>?[\\^`|".contains(&b)
+}
+
+fn needs_shell_quoting(name: &[u8], escape_unprintable: bool, escape_too: &[u8]) -> bool {
+    match name {
+        [] | [b'{' | b'}'] | [b'#' | b'~', ..] => return true,
+        _ => {}
+    }
+    units(name).any(|unit| match unit.bytes {
+        [b] if is_shell_special(*b) || escape_too.contains(b) => true,
+        _ => escape_unprintable && !unit.printable,
+    })
+}
+
+// Whether the name only contains characters that are treated the same way in both C and shell
+// quoting, in which case a name with an apostrophe in it can be written as "it's"
+fn is_c_and_shell_quote_compatible(name: &[u8]) -> bool {
+    units(name).enumerate().all(|(i, unit)| match unit.bytes {
+        [b'#' | b'~'] => i == 0,
+        [b'{' | b'}'] => name.len() == 1,
+        [b' ' | b'\''] => true,
+        [b] if is_shell_special(*b) => false,
+        _ => unit.printable,
+    })
+}
+
+fn quote_shell(name: &[u8], escape_unprintable: bool, escape_too: &[u8], buf: &mut Vec<u8>) {
+    if name.contains(&b'\'') && is_c_and_shell_quote_compatible(name) {
+        quote_c(name, true, escape_too, buf);
+        return;
+    }
+
+    // Unprintable characters are written as $'\n', which needs to be closed before continuing
+    // with the rest of the name in single quotes
+    let mut in_escape = false;
+    buf.push(b'\'');
+    for unit in units(name) {
+        if escape_unprintable && !unit.printable {
+            if !in_escape {
+                buf.extend_from_slice(b"'$'");
+                in_escape = true;
+            }
+            for b in unit.bytes {
+                push_escaped(*b, buf);
+            }
+            continue;
+        }
+        if unit.bytes == b"'" {
+            buf.extend_from_slice(b"'\\''");
+            in_escape = false;
+            continue;
+        }
+        if in_escape {
+            buf.extend_from_slice(b"''");
+            in_escape = false;
+        }
+        buf.extend_from_slice(unit.bytes);
+    }
+    buf.push(b'\'');
+}
+
+fn quote_c(name: &[u8], outer_quotes: bool, escape_too: &[u8], buf: &mut Vec<u8>) {
+    if outer_quotes {
+        buf.push(b'"');
+    }
+    for unit in units(name) {
+        match unit.bytes {
+            [b'"'] if outer_quotes => buf.extend_from_slice(b"\\\""),
+            [b'\\'] => buf.extend_from_slice(b"\\\\"),
+            [b] if escape_too.contains(b) => buf.extend_from_slice(&[b'\\', *b]),
+            bytes if !unit.printable => {
+                for b in bytes {
+                    push_escaped(*b, buf);
+                }
+            }
+            bytes => buf.extend_from_slice(bytes),
+        }
+    }
+    if outer_quotes {
+        buf.push(b'"');
+    }
+}
+
+fn push_escaped(b: u8, buf: &mut Vec<u8>) {
+    let letter = match b {
+        0x07 => b'a',
+        0x08 => b'b',
+        0x0C => b'f',
+        b'\n' => b'n',
+        b'\r' => b'r',
+        b'\t' => b't',
+        0x0B => b'v',
+        _ => {
+            buf.extend_from_slice(&[
+                b'\\',
+                b'0' + (b >> 6),
+                b'0' + ((b >> 3) & 7),
+                b'0' + (b & 7),
+            ]);
+            return;
+        }
+    };
+    buf.extend_from_slice(&[b'\\', letter]);
+}
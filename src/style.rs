@@ -1,48 +1,293 @@
 use crate::output::{OutputBuffer, Writable};
+use alloc::vec::Vec;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
-pub enum Style {
-    Reset,
-    Red,
-    Blue,
-    Magenta,
-    Cyan,
-    White,
-    Gray,
-    RedBold,
-    GreenBold,
-    YellowBold,
-    BlueBold,
-    MagentaBold,
-    CyanBold,
+pub enum Color {
+    // The 8 standard terminal colors, followed by their 8 bright variants
+    Ansi(u8),
     Fixed(u8),
+    Rgb(u8, u8, u8),
+}
+
+// Any combination of SGR attributes and colors. Two styles that are equal produce the same escape
+// sequence, which is what lets OutputBuffer skip writing it again.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Style {
+    // Bit n is set when SGR attribute n is, from 1 (bold) to 9 (crossed out)
+    attributes: u16,
+    foreground: Option<Color>,
+    background: Option<Color>,
 }
 
 impl Style {
+    pub const RESET: Style = Style {
+        attributes: 0,
+        foreground: None,
+        background: None,
+    };
+    pub const RED: Style = Style::foreground(Color::Ansi(1));
+    pub const BLUE: Style = Style::foreground(Color::Ansi(4));
+    pub const MAGENTA: Style = Style::foreground(Color::Ansi(5));
+    pub const CYAN: Style = Style::foreground(Color::Ansi(6));
+    pub const WHITE: Style = Style::foreground(Color::Ansi(7));
+    pub const GRAY: Style = Style::fixed(244);
+    pub const RED_BOLD: Style = Style::RED.bold();
+    pub const GREEN_BOLD: Style = Style::foreground(Color::Ansi(2)).bold();
+    pub const YELLOW_BOLD: Style = Style::foreground(Color::Ansi(3)).bold();
+    pub const BLUE_BOLD: Style = Style::BLUE.bold();
+    pub const MAGENTA_BOLD: Style = Style::MAGENTA.bold();
+    pub const CYAN_BOLD: Style = Style::CYAN.bold();
+
+    pub const fn foreground(color: Color) -> Self {
+        Style {
+            foreground: Some(color),
+            ..Style::RESET
+        }
+    }
+
+    pub const fn fixed(color: u8) -> Self {
+        Style::foreground(Color::Fixed(color))
+    }
+
+    pub const fn on(self, background: Color) -> Self {
+        Style {
+            background: Some(background),
+            ..self
+        }
+    }
+
+    pub const fn bold(self) -> Self {
+        Style {
+            attributes: self.attributes | 1 << 1,
+            ..self
+        }
+    }
+
+    // Parses the parameters of an SGR sequence, such as 01;38;2;255;128;0;48;5;236 from
+    // LS_COLORS. Parameters we don't understand are skipped, like a terminal would.
+    pub fn parse(parameters: &[u8]) -> Option<Self> {
+        let mut numbers = Vec::new();
+        for number in parameters.split(|b| *b == b';') {
+            if !number.iter().all(u8::is_ascii_digit) || number.len() > 3 {
+                return None;
+            }
+            numbers.push(crate::utils::atoi(number) as u16);
+        }
+
+        let mut style = Style::RESET;
+        let mut numbers = numbers.into_iter();
+        while let Some(n) = numbers.next() {
+            match n {
+                0 => style = Style::RESET,
+                1..=9 => style.attributes |= 1 << n,
+                22 => style.attributes &= !(1 << 1 | 1 << 2),
+                25 => style.attributes &= !(1 << 5 | 1 << 6),
+                23..=29 => style.attributes &= !(1 << (n - 20)),
+                30..=37 => style.foreground = Some(Color::Ansi((n - 30) as u8)),
+                38 => style.foreground = Some(parse_extended_color(&mut numbers)?),
+                39 => style.foreground = None,
+                40..=47 => style.background = Some(Color::Ansi((n - 40) as u8)),
+                48 => style.background = Some(parse_extended_color(&mut numbers)?),
+                49 => style.background = None,
+                90..=97 => style.foreground = Some(Color::Ansi((n - 90 + 8) as u8)),
+                100..=107 => style.background = Some(Color::Ansi((n - 100 + 8) as u8)),
+                _ => {}
+            }
+        }
+        Some(style)
+    }
+
     #[inline(never)]
     pub fn write_to(self, out: &mut OutputBuffer) {
-        use Style::*;
-        let bytes = match self {
-            Reset => &b"\x1B[m"[..],
-            Red => &b"\x1B[0;31m"[..],
-            Blue => &b"\x1B[0;34m"[..],
-            Magenta => &b"\x1B[0;35m"[..],
-            Cyan => &b"\x1B[0;36m"[..],
-            White => &b"\x1B[0;37m"[..],
-            Gray => &b"\x1B[0;38;5;244m"[..],
-            RedBold => &b"\x1B[1;31m"[..],
-            GreenBold => &b"\x1B[1;32m"[..],
-            YellowBold => &b"\x1B[1;33m"[..],
-            BlueBold => &b"\x1B[1;34m"[..],
-            MagentaBold => &b"\x1B[1;35m"[..],
-            CyanBold => &b"\x1B[1;36m"[..],
-            Fixed(c) => {
-                out.write(&b"\x1B[0;38;5;"[..]);
-                u64::from(c).write(out);
-                out.push(b'm');
-                return;
+        if self == Style::RESET {
+            out.write(&b"\x1B[m"[..]);
+            return;
+        }
+        // Every sequence starts from a reset, so that nothing carries over from the previous style
+        out.write(&b"\x1B[0"[..]);
+        for attribute in 1..=9u64 {
+            if self.attributes & 1 << attribute != 0 {
+                out.push(b';');
+                attribute.write(out);
+            }
+        }
+        if let Some(color) = self.foreground {
+            write_color(color, 30, out);
+        }
+        if let Some(color) = self.background {
+            write_color(color, 40, out);
+        }
+        out.push(b'm');
+    }
+}
+
+// The color after a 38 or 48, which is either 5;n or 2;r;g;b
+fn parse_extended_color(numbers: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    use core::convert::TryFrom;
+    let mut component = || numbers.next().and_then(|n| u8::try_from(n).ok());
+    match component()? {
+        5 => Some(Color::Fixed(component()?)),
+        2 => Some(Color::Rgb(component()?, component()?, component()?)),
+        _ => None,
+    }
+}
+
+fn write_color(color: Color, base: u64, out: &mut OutputBuffer) {
+    out.push(b';');
+    match color {
+        Color::Ansi(n @ 0..=7) => (base + u64::from(n)).write(out),
+        Color::Ansi(n) => (base + 60 + u64::from(n - 8)).write(out),
+        Color::Fixed(n) => {
+            (base + 8).write(out);
+            out.write(b";5;");
+            u64::from(n).write(out);
+        }
+        Color::Rgb(r, g, b) => {
+            (base + 8).write(out);
+            out.write(b";2");
+            for component in [r, g, b] {
+                out.push(b';');
+                u64::from(component).write(out);
             }
+        }
+    }
+}
+
+// The colors for each kind of file, either built into fls or taken from LS_COLORS
+pub struct LsColors {
+    pub file: Option<Style>,
+    pub directory: Style,
+    pub link: Style,
+    pub orphan: Style,
+    pub missing: Option<Style>,
+    pub fifo: Style,
+    pub socket: Style,
+    pub block_device: Style,
+    pub char_device: Style,
+    pub executable: Option<Style>,
+    // Like GNU ls, these are only checked for when they are colored
+    pub setuid: Option<Style>,
+    pub setgid: Option<Style>,
+    pub capability: Option<Style>,
+    pub multi_hardlink: Option<Style>,
+    pub sticky: Option<Style>,
+    pub other_writable: Option<Style>,
+    pub sticky_other_writable: Option<Style>,
+    // Suffixes like .tar or ~; if this is None, the extension table built into fls is used
+    pub suffixes: Option<Vec<(&'static [u8], Style)>>,
+}
+
+impl LsColors {
+    // Whether any color depends on permission bits or link counts, which d_type doesn't tell us
+    pub fn needs_mode(&self) -> bool {
+        [
+            self.executable,
+            self.setuid,
+            self.setgid,
+            self.capability,
+            self.multi_hardlink,
+            self.sticky,
+            self.other_writable,
+            self.sticky_other_writable,
+        ]
+        .iter()
+        .any(Option::is_some)
+    }
+
+    pub fn builtin() -> Self {
+        Self {
+            file: None,
+            directory: Style::BLUE_BOLD,
+            link: Style::CYAN_BOLD,
+            orphan: Style::RED_BOLD,
+            missing: None,
+            fifo: Style::YELLOW_BOLD,
+            socket: Style::MAGENTA_BOLD,
+            block_device: Style::YELLOW_BOLD,
+            char_device: Style::YELLOW_BOLD,
+            executable: Some(Style::GREEN_BOLD),
+            setuid: Some(Style::WHITE.on(Color::Ansi(1))),
+            setgid: Some(Style::foreground(Color::Ansi(0)).on(Color::Ansi(3))),
+            capability: None,
+            multi_hardlink: None,
+            sticky: Some(Style::WHITE.on(Color::Ansi(4))),
+            other_writable: Some(Style::BLUE.on(Color::Ansi(2))),
+            sticky_other_writable: Some(Style::foreground(Color::Ansi(0)).on(Color::Ansi(2))),
+            suffixes: None,
+        }
+    }
+
+    // Parses the format written by dircolors, like di=01;34:ln=01;36:*.tar=01;31. Types that
+    // LS_COLORS doesn't mention keep their built-in color.
+    pub fn parse(spec: &'static [u8]) -> Self {
+        let mut colors = Self::builtin();
+        let mut suffixes = Vec::new();
+        for entry in spec.split(|b| *b == b':') {
+            let (key, value) = match entry.iter().position(|b| *b == b'=') {
+                Some(i) => (&entry[..i], &entry[i + 1..]),
+                None => continue,
+            };
+            // Like GNU ls, an empty value or 0 means that these files aren't colored
+            let style = match Style::parse(value) {
+                Some(Style::RESET) => None,
+                Some(style) => Some(style),
+                None => continue,
+            };
+            if let Some(suffix) = key.strip_prefix(b"*") {
+                suffixes.push((suffix, style.unwrap_or(Style::RESET)));
+                continue;
+            }
+            let uncolored = style.unwrap_or(Style::RESET);
+            match key {
+                b"fi" => colors.file = style,
+                b"di" => colors.directory = uncolored,
+                // ln=target means symlinks are colored like what they point to, which we don't
+                // support, so they keep the built-in color
+                b"ln" if value != b"target" => colors.link = uncolored,
+                b"or" => colors.orphan = uncolored,
+                b"mi" => colors.missing = style,
+                b"pi" => colors.fifo = uncolored,
+                b"so" => colors.socket = uncolored,
+                b"bd" => colors.block_device = uncolored,
+                b"cd" => colors.char_device = uncolored,
+                b"ex" => colors.executable = style,
+                b"su" => colors.setuid = style,
+                b"sg" => colors.setgid = style,
+                b"ca" => colors.capability = style,
+                b"mh" => colors.multi_hardlink = style,
+                b"st" => colors.sticky = style,
+                b"ow" => colors.other_writable = style,
+                b"tw" => colors.sticky_other_writable = style,
+                _ => {}
+            }
+        }
+        colors.suffixes = Some(suffixes);
+        colors
+    }
+
+    // The style of a regular file which isn't colored because of its type
+    pub fn file_style(&self, name: &[u8]) -> Style {
+        let suffixes = match &self.suffixes {
+            Some(suffixes) => suffixes,
+            None => return crate::directory::extension_style(name),
+        };
+        // Later entries override earlier ones, and a match with the same case beats one without
+        let matches = |case_sensitive: bool| {
+            suffixes.iter().rev().find(|(suffix, _)| {
+                name.len() >= suffix.len() && {
+                    let end = &name[name.len() - suffix.len()..];
+                    if case_sensitive {
+                        end == *suffix
+                    } else {
+                        end.eq_ignore_ascii_case(suffix)
+                    }
+                }
+            })
         };
-        out.write(bytes);
+        matches(true)
+            .or_else(|| matches(false))
+            .map(|(_, style)| *style)
+            .or(self.file)
+            .unwrap_or(Style::RESET)
     }
 }
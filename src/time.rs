@@ -1,10 +1,6 @@
-#![allow(unused)] // File is very WIP
-use crate::CStr;
+use crate::{locale::TimeLocale, CStr};
 use alloc::vec::Vec;
-use core::{
-    convert::TryInto,
-    sync::atomic::{AtomicI64, Ordering::SeqCst},
-};
+use core::convert::TryInto;
 
 const LEAPOCH: i64 = 946684800i64 + 86400 * (31 + 29);
 
@@ -14,90 +10,132 @@ const DAYS_PER_4Y: i64 = 365 * 4 + 1;
 
 const DAYS_IN_MONTH: [u8; 12] = [31, 30, 31, 30, 31, 31, 30, 31, 30, 31, 31, 29];
 
-// Hacked up components from tzfile: https://github.com/nicolasbauw/rs-tzfile
-
-fn parse_header(buffer: &[u8]) -> Header {
-    let magic = read_u32(&buffer[0x00..=0x03]);
-    if magic != MAGIC {
-        panic!("Not a TZ file");
-    }
-    if buffer[4] != 50 {
-        panic!("Unsupported TZ format");
-    }
-    let tzh_ttisgmtcnt = read_i32(&buffer[0x14..=0x17]) as usize;
-    let tzh_ttisstdcnt = read_i32(&buffer[0x18..=0x1B]) as usize;
-    let tzh_leapcnt = read_i32(&buffer[0x1C..=0x1F]) as usize;
-    let tzh_timecnt = read_i32(&buffer[0x20..=0x23]) as usize;
-    let tzh_typecnt = read_i32(&buffer[0x24..=0x27]) as usize;
-    let tzh_charcnt = read_i32(&buffer[0x28..=0x2b]) as usize;
-    // V2 format data start
-    let s: usize = tzh_timecnt * 5
-        + tzh_typecnt * 6
-        + tzh_leapcnt * 8
-        + tzh_charcnt
-        + tzh_ttisstdcnt
-        + tzh_ttisgmtcnt
-        + 44;
-    Header {
-        tzh_ttisgmtcnt: read_i32(&buffer[s + 0x14..=s + 0x17]) as usize,
-        tzh_ttisstdcnt: read_i32(&buffer[s + 0x18..=s + 0x1B]) as usize,
-        tzh_leapcnt: read_i32(&buffer[s + 0x1C..=s + 0x1F]) as usize,
-        tzh_timecnt: read_i32(&buffer[s + 0x20..=s + 0x23]) as usize,
-        tzh_typecnt: read_i32(&buffer[s + 0x24..=s + 0x27]) as usize,
-        tzh_charcnt: read_i32(&buffer[s + 0x28..=s + 0x2b]) as usize,
-        v2_header_start: s,
-    }
-}
-
-fn parse_data(buffer: &[u8], header: Header) -> Tzinfo {
-    // Calculates fields lengths and indexes (Version 2 format)
-    let tzh_timecnt_len: usize = header.tzh_timecnt * 9;
-    let tzh_typecnt_len: usize = header.tzh_typecnt * 6;
-    let tzh_leapcnt_len: usize = header.tzh_leapcnt * 12;
-    let tzh_charcnt_len: usize = header.tzh_charcnt;
-    let tzh_timecnt_end: usize = HEADER_LEN + header.v2_header_start + tzh_timecnt_len;
-    let tzh_typecnt_end: usize = tzh_timecnt_end + tzh_typecnt_len;
-    let tzh_leapcnt_end: usize = tzh_typecnt_end + tzh_leapcnt_len;
-    let tzh_charcnt_end: usize = tzh_leapcnt_end + tzh_charcnt_len;
-
-    // Extracting data fields
-    let tzh_timecnt_data: Vec<i64> = buffer[HEADER_LEN + header.v2_header_start
-        ..HEADER_LEN + header.v2_header_start + header.tzh_timecnt * 8]
-        .chunks_exact(8)
-        .map(read_i64)
-        .collect();
+// A TZif reader following RFC 8536. Every length is checked, so a malformed file is rejected
+// instead of panicking.
 
-    let tzh_timecnt_indices: &[u8] =
-        &buffer[HEADER_LEN + header.v2_header_start + header.tzh_timecnt * 8..tzh_timecnt_end];
+const MAGIC: &[u8] = b"TZif";
+const HEADER_LEN: usize = 44;
 
-    let abbrs = &buffer[tzh_leapcnt_end..tzh_charcnt_end];
+struct Header {
+    version: u8,
+    isutcnt: usize,
+    isstdcnt: usize,
+    leapcnt: usize,
+    timecnt: usize,
+    typecnt: usize,
+    charcnt: usize,
+}
 
-    let tzh_typecnt: Vec<Ttinfo> = buffer[tzh_timecnt_end..tzh_typecnt_end]
-        .chunks_exact(6)
-        .map(|tti| {
-            let offset = tti[5];
-            let index = abbrs
-                .iter()
-                .take(offset as usize)
-                .filter(|x| **x == b'\0')
-                .count();
-            Ttinfo {
-                tt_gmtoff: read_i32(&tti[0..4]) as isize,
-                tt_isdst: tti[4],
-                tt_abbrind: index as u8,
+impl Header {
+    fn parse(data: &[u8]) -> Option<(Header, &[u8])> {
+        let (header, rest) = split(data, HEADER_LEN)?;
+        if &header[..4] != MAGIC {
+            return None;
+        }
+        let count = |i: usize| read_u32(&header[20 + i * 4..]) as usize;
+        let header_data = Header {
+            version: header[4],
+            isutcnt: count(0),
+            isstdcnt: count(1),
+            leapcnt: count(2),
+            timecnt: count(3),
+            typecnt: count(4),
+            charcnt: count(5),
+        };
+        if header_data.typecnt == 0 || header_data.charcnt == 0 {
+            return None;
+        }
+        Some((header_data, rest))
+    }
+
+    fn data_len(&self, time_size: usize) -> usize {
+        self.timecnt * (time_size + 1)
+            + self.typecnt * 6
+            + self.charcnt
+            + self.leapcnt * (time_size + 4)
+            + self.isstdcnt
+            + self.isutcnt
+    }
+}
+
+fn parse_tzif(data: &[u8]) -> Option<Tzinfo> {
+    let (header, rest) = Header::parse(data)?;
+    // Version 1 files only have 32-bit times, later versions repeat everything with 64-bit
+    // times and add a POSIX TZ string for times after the last transition
+    let (header, rest, time_size) = if header.version == 0 {
+        (header, rest, 4)
+    } else {
+        let (_, rest) = split(rest, header.data_len(4))?;
+        let (header, rest) = Header::parse(rest)?;
+        (header, rest, 8)
+    };
+
+    let (times, rest) = split(rest, header.timecnt * time_size)?;
+    let (indices, rest) = split(rest, header.timecnt)?;
+    let (types, rest) = split(rest, header.typecnt * 6)?;
+    let (abbrs, rest) = split(rest, header.charcnt)?;
+    let (_, rest) = split(
+        rest,
+        header.leapcnt * (time_size + 4) + header.isstdcnt + header.isutcnt,
+    )?;
+
+    let tzh_timecnt_data: Vec<i64> = times
+        .chunks_exact(time_size)
+        .map(|time| {
+            if time_size == 4 {
+                i64::from(read_u32(time) as i32)
+            } else {
+                read_i64(time)
             }
         })
         .collect();
+    if tzh_timecnt_data.windows(2).any(|w| w[0] >= w[1])
+        || indices.iter().any(|i| usize::from(*i) >= header.typecnt)
+    {
+        return None;
+    }
 
-    Tzinfo {
+    let tzh_typecnt = types
+        .chunks_exact(6)
+        .map(|tti| Ttinfo {
+            tt_gmtoff: read_u32(tti) as i32 as isize,
+            tt_abbrind: usize::from(tti[5]),
+        })
+        .collect::<Vec<_>>();
+    if tzh_typecnt.iter().any(|tti| tti.tt_abbrind >= abbrs.len()) {
+        return None;
+    }
+
+    let mut tzinfo = Tzinfo {
         tzh_timecnt_data,
-        tzh_timecnt_indices: tzh_timecnt_indices.to_vec(),
+        tzh_timecnt_indices: indices.to_vec(),
         tzh_typecnt,
+        abbrs: abbrs.to_vec(),
+        rule: None,
+    };
+
+    // The footer is the TZ string between two newlines; an empty one means no rule
+    let footer = rest
+        .strip_prefix(b"\n")
+        .and_then(|footer| Some(&footer[..footer.iter().position(|b| *b == b'\n')?]));
+    if let Some(footer) = footer.filter(|footer| !footer.is_empty()) {
+        let types = tzinfo.tzh_typecnt.len();
+        let abbrs = tzinfo.abbrs.len();
+        if !tzinfo.add_rule(footer) {
+            tzinfo.tzh_typecnt.truncate(types);
+            tzinfo.abbrs.truncate(abbrs);
+        }
     }
+
+    Some(tzinfo)
 }
 
-fn read_i32(bytes: &[u8]) -> i32 {
-    i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+fn split(data: &[u8], len: usize) -> Option<(&[u8], &[u8])> {
+    if len <= data.len() {
+        Some(data.split_at(len))
+    } else {
+        None
+    }
 }
 
 fn read_u32(bytes: &[u8]) -> u32 {
@@ -108,19 +146,6 @@ fn read_i64(bytes: &[u8]) -> i64 {
     i64::from_be_bytes(bytes[..8].try_into().unwrap())
 }
 
-const MAGIC: u32 = 0x545A6966;
-const HEADER_LEN: usize = 0x2C;
-
-struct Header {
-    tzh_ttisgmtcnt: usize,
-    tzh_ttisstdcnt: usize,
-    tzh_leapcnt: usize,
-    tzh_timecnt: usize,
-    tzh_typecnt: usize,
-    tzh_charcnt: usize,
-    v2_header_start: usize,
-}
-
 pub struct Tzinfo {
     /// transition times timestamps table
     tzh_timecnt_data: Vec<i64>,
@@ -128,38 +153,198 @@ pub struct Tzinfo {
     tzh_timecnt_indices: Vec<u8>,
     /// a struct containing UTC offset, daylight saving time, abbreviation index
     tzh_typecnt: Vec<Ttinfo>,
+    /// the time zone abbreviations, each terminated by a NUL
+    abbrs: Vec<u8>,
+    /// a POSIX TZ rule for times after the last transition
+    rule: Option<Rule>,
 }
 
-pub struct LocalTime {
-    pub year: i32,
+struct Rule {
+    /// index of the standard time Ttinfo
+    std: usize,
+    dst: Option<DstRule>,
+}
+
+struct DstRule {
+    /// index of the daylight saving time Ttinfo
+    ttinfo: usize,
+    /// when DST starts, in local standard time
+    start: (RuleDate, i64),
+    /// when DST ends, in local daylight saving time
+    end: (RuleDate, i64),
+}
+
+#[derive(Clone, Copy)]
+enum RuleDate {
+    /// Jn: 1 to 365, never counting February 29
+    Julian(i64),
+    /// n: 0 to 365, counting February 29 in leap years
+    ZeroBased(i64),
+    /// Mm.w.d: day d (0 is Sunday) of week w (5 is the last) of month m
+    MonthWeekDay(i64, i64, i64),
+}
+
+pub struct LocalTime<'a> {
+    /// years since 1900
+    pub year: i64,
+    /// months since January
     pub month: i32,
     pub day_of_month: i32,
     pub hour: i32,
     pub minute: i32,
+    pub second: i32,
+    pub nanosecond: u32,
+    /// days since Sunday
+    pub day_of_week: i32,
+    /// days since January 1
+    pub day_of_year: i32,
+    pub gmt_offset: i64,
+    /// like UTC or CEST
+    pub zone: &'a [u8],
 }
 
 impl Tzinfo {
-    pub fn new() -> Self {
-        let zi = crate::utils::fs_read(CStr::from_bytes(&b"/etc/localtime\0"[..])).unwrap();
-        let header = parse_header(&zi);
-        parse_data(&zi, header)
+    // Resolves the TZ environment variable the way glibc does, falling back to UTC
+    pub fn new(tz: Option<&[u8]>, tzdir: Option<&[u8]>) -> Self {
+        let tz = match tz {
+            None => &b"/etc/localtime"[..],
+            Some(tz) => tz.strip_prefix(b":").unwrap_or(tz),
+        };
+        if tz.is_empty() {
+            return Self::from_rule(b"UTC0");
+        }
+
+        let mut path = Vec::new();
+        if !tz.starts_with(b"/") {
+            path.extend_from_slice(tzdir.unwrap_or(b"/usr/share/zoneinfo"));
+            path.push(b'/');
+        }
+        path.extend_from_slice(tz);
+        path.push(0);
+        if let Some(tzinfo) = crate::utils::fs_read(CStr::from_bytes(&path))
+            .ok()
+            .and_then(|zi| parse_tzif(&zi))
+        {
+            return tzinfo;
+        }
+
+        Self::from_rule(tz)
+    }
+
+    // Like glibc, a zone name without an offset means UTC under that name
+    fn from_rule(spec: &[u8]) -> Self {
+        let mut tzinfo = Tzinfo {
+            tzh_timecnt_data: Vec::new(),
+            tzh_timecnt_indices: Vec::new(),
+            tzh_typecnt: Vec::new(),
+            abbrs: Vec::new(),
+            rule: None,
+        };
+        if !tzinfo.add_rule(spec) {
+            let name = match parse_zone_name(spec) {
+                Some((name, _)) => name,
+                None => &b"UTC"[..],
+            };
+            tzinfo.tzh_typecnt.clear();
+            tzinfo.abbrs.clear();
+            tzinfo.push_ttinfo(name, 0);
+            tzinfo.rule = Some(Rule { std: 0, dst: None });
+        }
+        tzinfo
     }
 
-    fn gmt_offset(&self, time: i64) -> i64 {
-        let best_idx = match self.tzh_timecnt_data.binary_search(&time) {
-            Ok(i) => i,
-            Err(i) => i + 1,
+    fn push_ttinfo(&mut self, abbr: &[u8], gmt_offset: i64) -> usize {
+        self.tzh_typecnt.push(Ttinfo {
+            tt_gmtoff: gmt_offset as isize,
+            tt_abbrind: self.abbrs.len(),
+        });
+        self.abbrs.extend_from_slice(abbr);
+        self.abbrs.push(0);
+        self.tzh_typecnt.len() - 1
+    }
+
+    // Parses a POSIX TZ string like EST5EDT,M3.2.0,M11.1.0 into self.rule
+    fn add_rule(&mut self, spec: &[u8]) -> bool {
+        let (std_name, rest) = match parse_zone_name(spec) {
+            Some(parsed) => parsed,
+            None => return false,
+        };
+        // POSIX offsets are positive west of Greenwich
+        let (std_offset, rest) = match parse_offset(rest) {
+            Some((offset, rest)) => (-offset, rest),
+            None => return false,
+        };
+        let std = self.push_ttinfo(std_name, std_offset);
+        if rest.is_empty() {
+            self.rule = Some(Rule { std, dst: None });
+            return true;
+        }
+
+        let (dst_name, rest) = match parse_zone_name(rest) {
+            Some(parsed) => parsed,
+            None => return false,
+        };
+        let (dst_offset, rest) = match parse_offset(rest) {
+            Some((offset, rest)) => (-offset, rest),
+            None => (std_offset + 3600, rest),
         };
-        let idx = self.tzh_timecnt_indices[best_idx] as usize;
-        self.tzh_typecnt[idx].tt_gmtoff as i64
+        // Without rules, glibc uses the ones from the United States
+        let rest = if rest.is_empty() {
+            &b",M3.2.0,M11.1.0"[..]
+        } else {
+            rest
+        };
+        let (start, rest) = match rest.strip_prefix(b",").and_then(parse_rule_date) {
+            Some(parsed) => parsed,
+            None => return false,
+        };
+        let (end, rest) = match rest.strip_prefix(b",").and_then(parse_rule_date) {
+            Some(parsed) => parsed,
+            None => return false,
+        };
+        if !rest.is_empty() {
+            return false;
+        }
+        let ttinfo = self.push_ttinfo(dst_name, dst_offset);
+        self.rule = Some(Rule {
+            std,
+            dst: Some(DstRule { ttinfo, start, end }),
+        });
+        true
+    }
+
+    fn ttinfo(&self, time: i64) -> &Ttinfo {
+        let transitions = &self.tzh_timecnt_data;
+        let idx = match transitions.binary_search(&time) {
+            Ok(i) => usize::from(self.tzh_timecnt_indices[i]),
+            // Times before the first transition use the first type
+            Err(0) if !transitions.is_empty() => 0,
+            Err(i) if i < transitions.len() => usize::from(self.tzh_timecnt_indices[i - 1]),
+            _ => match &self.rule {
+                Some(rule) => rule.ttinfo(time, &self.tzh_typecnt),
+                None => transitions
+                    .len()
+                    .checked_sub(1)
+                    .map_or(0, |last| usize::from(self.tzh_timecnt_indices[last])),
+            },
+        };
+        &self.tzh_typecnt[idx]
     }
 
     // Ported from musl's localtime_r impl
     #[inline(never)]
-    pub fn convert_to_localtime(&self, t: i64) -> LocalTime {
-        let t = t + self.gmt_offset(t);
-
-        let secs = t - LEAPOCH;
+    pub fn convert_to_localtime(&self, t: i64) -> LocalTime<'_> {
+        let ttinfo = self.ttinfo(t);
+        let gmt_offset = ttinfo.tt_gmtoff as i64;
+        let zone = self
+            .abbrs
+            .get(ttinfo.tt_abbrind..)
+            .and_then(|abbr| abbr.split(|b| *b == 0).next())
+            .unwrap_or_default();
+
+        let t = t.saturating_add(gmt_offset);
+
+        let secs = t.saturating_sub(LEAPOCH);
         let mut days = secs / 86400;
         let mut remsecs = secs % 86400;
         if remsecs < 0 {
@@ -167,6 +352,11 @@ impl Tzinfo {
             days -= 1;
         }
 
+        let mut day_of_week = (3 + days) % 7;
+        if day_of_week < 0 {
+            day_of_week += 7;
+        }
+
         let mut qc_cycles = days / DAYS_PER_400Y;
         let mut remdays = days % DAYS_PER_400Y;
         if remdays < 0 {
@@ -192,10 +382,16 @@ impl Tzinfo {
         }
         remdays -= remyears * 365;
 
-        let leap = remyears != 0 && ((q_cycles == 0) || c_cycles != 0);
+        // Years here start in March, so the leap day ends the year before a remyears of 0
+        let leap = remyears == 0 && (q_cycles != 0 || c_cycles == 0);
 
         let mut years = remyears + 4 * q_cycles + 100 * c_cycles + 400 * qc_cycles;
 
+        let mut day_of_year = remdays + 31 + 28 + leap as i64;
+        if day_of_year >= 365 + leap as i64 {
+            day_of_year -= 365 + leap as i64;
+        }
+
         let mut months: i64 = 0;
         while DAYS_IN_MONTH[months as usize] as i64 <= remdays {
             remdays -= DAYS_IN_MONTH[months as usize] as i64;
@@ -208,17 +404,261 @@ impl Tzinfo {
         }
 
         LocalTime {
-            year: (years + 100).try_into().unwrap(),
+            year: years + 100,
             month: (months + 2).try_into().unwrap(),
             day_of_month: (remdays + 1).try_into().unwrap(),
             hour: (remsecs / 3600).try_into().unwrap(),
             minute: (remsecs / 60 % 60).try_into().unwrap(),
+            second: (remsecs % 60).try_into().unwrap(),
+            nanosecond: 0,
+            day_of_week: day_of_week.try_into().unwrap(),
+            day_of_year: day_of_year.try_into().unwrap(),
+            gmt_offset,
+            zone,
         }
     }
 }
 
 struct Ttinfo {
     tt_gmtoff: isize,
-    tt_isdst: u8,
-    tt_abbrind: u8,
+    tt_abbrind: usize,
+}
+
+impl Rule {
+    // Returns the index of the Ttinfo in effect at a UTC time
+    fn ttinfo(&self, time: i64, types: &[Ttinfo]) -> usize {
+        let dst = match &self.dst {
+            Some(dst) => dst,
+            None => return self.std,
+        };
+        let std_offset = types[self.std].tt_gmtoff as i64;
+        let dst_offset = types[dst.ttinfo].tt_gmtoff as i64;
+        let year = year_of(time.saturating_add(std_offset));
+        let start = rule_time(year, dst.start).saturating_sub(std_offset);
+        let end = rule_time(year, dst.end).saturating_sub(dst_offset);
+        let in_dst = if start < end {
+            start <= time && time < end
+        } else {
+            // In the southern hemisphere, DST spans the new year
+            !(end <= time && time < start)
+        };
+        if in_dst {
+            dst.ttinfo
+        } else {
+            self.std
+        }
+    }
+}
+
+// A name is at least three letters, or anything alphanumeric, + or - between < and >
+fn parse_zone_name(spec: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (name, rest) = if let Some(quoted) = spec.strip_prefix(b"<") {
+        let len = quoted.iter().position(|b| *b == b'>')?;
+        if !quoted[..len]
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || *b == b'+' || *b == b'-')
+        {
+            return None;
+        }
+        (&quoted[..len], &quoted[len + 1..])
+    } else {
+        let len = spec
+            .iter()
+            .position(|b| !b.is_ascii_alphabetic())
+            .unwrap_or(spec.len());
+        (&spec[..len], &spec[len..])
+    };
+    if name.len() < 3 {
+        return None;
+    }
+    Some((name, rest))
+}
+
+// Parses [+-]hh[:mm[:ss]] into seconds
+fn parse_offset(spec: &[u8]) -> Option<(i64, &[u8])> {
+    let (sign, mut rest) = match spec.first() {
+        Some(b'-') => (-1, &spec[1..]),
+        Some(b'+') => (1, &spec[1..]),
+        _ => (1, spec),
+    };
+    let mut seconds = 0;
+    for (i, unit) in [3600, 60, 1].iter().enumerate() {
+        if i > 0 {
+            match rest.strip_prefix(b":") {
+                Some(after) => rest = after,
+                None => break,
+            }
+        }
+        let (value, after) = parse_number(rest)?;
+        seconds += value * unit;
+        rest = after;
+    }
+    Some((sign * seconds, rest))
+}
+
+fn parse_number(spec: &[u8]) -> Option<(i64, &[u8])> {
+    let len = spec
+        .iter()
+        .position(|b| !b.is_ascii_digit())
+        .unwrap_or(spec.len());
+    if len == 0 || len > 3 {
+        return None;
+    }
+    let value = spec[..len]
+        .iter()
+        .fold(0, |value, b| value * 10 + i64::from(b - b'0'));
+    Some((value, &spec[len..]))
+}
+
+// Parses a date and an optional /time, which defaults to 02:00:00
+fn parse_rule_date(spec: &[u8]) -> Option<((RuleDate, i64), &[u8])> {
+    let (date, rest) = if let Some(rest) = spec.strip_prefix(b"J") {
+        let (day, rest) = parse_number(rest)?;
+        if !(1..=365).contains(&day) {
+            return None;
+        }
+        (RuleDate::Julian(day), rest)
+    } else if let Some(rest) = spec.strip_prefix(b"M") {
+        let (month, rest) = parse_number(rest)?;
+        let (week, rest) = parse_number(rest.strip_prefix(b".")?)?;
+        let (day, rest) = parse_number(rest.strip_prefix(b".")?)?;
+        if !(1..=12).contains(&month) || !(1..=5).contains(&week) || day > 6 {
+            return None;
+        }
+        (RuleDate::MonthWeekDay(month, week, day), rest)
+    } else {
+        let (day, rest) = parse_number(spec)?;
+        if day > 365 {
+            return None;
+        }
+        (RuleDate::ZeroBased(day), rest)
+    };
+    match rest.strip_prefix(b"/") {
+        Some(rest) => {
+            let (time, rest) = parse_offset(rest)?;
+            Some(((date, time), rest))
+        }
+        None => Some(((date, 7200), rest)),
+    }
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar, with months from 1
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * DAYS_PER_400Y + day_of_era - 719468
+}
+
+// The inverse of days_from_civil, keeping only the year
+fn year_of(time: i64) -> i64 {
+    let days = time.div_euclid(86400) + 719468;
+    let era = days.div_euclid(DAYS_PER_400Y);
+    let day_of_era = days - era * DAYS_PER_400Y;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Years start in March here, so January and February belong to the next one
+    let january_or_february = (5 * day_of_year + 2) / 153 >= 10;
+    era * 400 + year_of_era + january_or_february as i64
+}
+
+// The local time in seconds since the epoch at which a rule takes effect in a year
+fn rule_time(year: i64, (date, time): (RuleDate, i64)) -> i64 {
+    let days = match date {
+        RuleDate::Julian(day) => {
+            let leap_day = (is_leap_year(year) && day >= 60) as i64;
+            days_from_civil(year, 1, 1) + day - 1 + leap_day
+        }
+        RuleDate::ZeroBased(day) => days_from_civil(year, 1, 1) + day,
+        RuleDate::MonthWeekDay(month, week, weekday) => {
+            let first = days_from_civil(year, month, 1);
+            // 1970-01-01 was a Thursday
+            let first_weekday = (first + 4).rem_euclid(7);
+            let mut day = first + (weekday - first_weekday).rem_euclid(7) + (week - 1) * 7;
+            let next_month = if month == 12 {
+                days_from_civil(year + 1, 1, 1)
+            } else {
+                days_from_civil(year, month + 1, 1)
+            };
+            while day >= next_month {
+                day -= 7;
+            }
+            day
+        }
+    };
+    days.saturating_mul(86400).saturating_add(time)
+}
+
+// Formats a time like strftime. Like glibc, unknown conversions are copied as-is.
+pub fn strftime(format: &[u8], time: &LocalTime, locale: &TimeLocale, out: &mut Vec<u8>) {
+    let name = |names: &[&'static [u8]], i: i32| names.get(i as usize).copied().unwrap_or(b"?");
+    let month = time.month;
+    let weekday = time.day_of_week;
+    let hour_12 = match time.hour % 12 {
+        0 => 12,
+        hour => hour,
+    };
+
+    let mut bytes = format.iter().copied();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'Y') => push_number(out, time.year + 1900, 1, b'0'),
+            Some(b'y') => push_number(out, (time.year + 1900).rem_euclid(100), 2, b'0'),
+            Some(b'm') => push_number(out, i64::from(time.month) + 1, 2, b'0'),
+            Some(b'd') => push_number(out, i64::from(time.day_of_month), 2, b'0'),
+            Some(b'e') => push_number(out, i64::from(time.day_of_month), 2, b' '),
+            Some(b'H') => push_number(out, i64::from(time.hour), 2, b'0'),
+            Some(b'I') => push_number(out, i64::from(hour_12), 2, b'0'),
+            Some(b'M') => push_number(out, i64::from(time.minute), 2, b'0'),
+            Some(b'S') => push_number(out, i64::from(time.second), 2, b'0'),
+            Some(b'N') => push_number(out, i64::from(time.nanosecond), 9, b'0'),
+            Some(b'j') => push_number(out, i64::from(time.day_of_year) + 1, 3, b'0'),
+            Some(b'u') => push_number(out, i64::from((time.day_of_week + 6) % 7 + 1), 1, b'0'),
+            Some(b'w') => push_number(out, i64::from(time.day_of_week), 1, b'0'),
+            Some(b'p') => out.extend_from_slice(if time.hour < 12 { b"AM" } else { b"PM" }),
+            Some(b'b' | b'h') => out.extend_from_slice(name(&locale.abbreviated_months, month)),
+            Some(b'B') => out.extend_from_slice(name(&locale.months, month)),
+            Some(b'a') => out.extend_from_slice(name(&locale.abbreviated_days, weekday)),
+            Some(b'A') => out.extend_from_slice(name(&locale.days, weekday)),
+            Some(b'z') => {
+                let offset = time.gmt_offset;
+                out.push(if offset < 0 { b'-' } else { b'+' });
+                let minutes = offset.abs() / 60;
+                push_number(out, minutes / 60 * 100 + minutes % 60, 4, b'0');
+            }
+            Some(b'Z') => out.extend_from_slice(time.zone),
+            Some(b'F') => strftime(b"%Y-%m-%d", time, locale, out),
+            Some(b'T') => strftime(b"%H:%M:%S", time, locale, out),
+            Some(b'R') => strftime(b"%H:%M", time, locale, out),
+            Some(b'D') => strftime(b"%m/%d/%y", time, locale, out),
+            Some(b'n') => out.push(b'\n'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'%') => out.push(b'%'),
+            Some(other) => out.extend_from_slice(&[b'%', other]),
+            None => out.push(b'%'),
+        }
+    }
+}
+
+fn push_number(out: &mut Vec<u8>, value: i64, width: usize, pad: u8) {
+    if value < 0 {
+        out.push(b'-');
+    }
+    let mut buf = crate::utils::Buffer::new();
+    let digits = buf.format(value.unsigned_abs());
+    for _ in digits.len()..width {
+        out.push(pad);
+    }
+    out.extend_from_slice(digits);
 }
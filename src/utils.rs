@@ -79,6 +79,267 @@ pub fn fs_read(path: CStr<'_>) -> Result<Vec<u8>, crate::Error> {
     while bytes_read < len {
         bytes_read += read(fd, &mut contents[bytes_read..])?;
     }
+    // Files in /proc report a size of 0, so those have to be read until we hit the end
+    if len == 0 {
+        loop {
+            contents.resize(bytes_read + 4096, 0);
+            match read(fd, &mut contents[bytes_read..])? {
+                0 => break,
+                n => bytes_read += n,
+            }
+        }
+        contents.truncate(bytes_read);
+    }
     close(fd)?;
     Ok(contents)
 }
+
+// veneer doesn't have statx, so we make the syscall ourselves
+pub fn statx(
+    dirfd: libc::c_int,
+    name: CStr<'_>,
+    flags: libc::c_int,
+    mask: libc::c_uint,
+) -> Result<libc::statx, crate::Error> {
+    unsafe {
+        let mut status: libc::statx = core::mem::zeroed();
+        let ret = sc::syscall!(
+            STATX,
+            dirfd,
+            name.as_ptr(),
+            flags,
+            mask,
+            &mut status as *mut libc::statx
+        ) as isize;
+        if ret < 0 {
+            Err(crate::Error(-ret as i32))
+        } else {
+            Ok(status)
+        }
+    }
+}
+
+// There is no lgetxattrat, so files in a directory are reached through /proc/self/fd
+fn proc_path(dirfd: libc::c_int, name: CStr<'_>) -> Vec<u8> {
+    let name = name.as_bytes();
+    let mut path = Vec::with_capacity(name.len() + 24);
+    if dirfd != libc::AT_FDCWD && !name.starts_with(b"/") {
+        path.extend_from_slice(b"/proc/self/fd/");
+        path.extend_from_slice(Buffer::new().format(dirfd as u64));
+        path.push(b'/');
+    }
+    path.extend_from_slice(name);
+    path.push(0);
+    path
+}
+
+// Reads an extended attribute like security.capability\0 without following symlinks
+pub fn lgetxattr(
+    dirfd: libc::c_int,
+    name: CStr<'_>,
+    attribute: &[u8],
+    value: &mut [u8],
+) -> Result<usize, crate::Error> {
+    getxattr(dirfd, name, attribute, value, false)
+}
+
+pub fn getxattr(
+    dirfd: libc::c_int,
+    name: CStr<'_>,
+    attribute: &[u8],
+    value: &mut [u8],
+    follow_symlinks: bool,
+) -> Result<usize, crate::Error> {
+    let path = proc_path(dirfd, name);
+    let (path, attribute, len) = (path.as_ptr(), attribute.as_ptr(), value.len());
+    let value = value.as_mut_ptr();
+    unsafe {
+        let ret = if follow_symlinks {
+            sc::syscall!(GETXATTR, path, attribute, value, len)
+        } else {
+            sc::syscall!(LGETXATTR, path, attribute, value, len)
+        } as isize;
+        if ret < 0 {
+            Err(crate::Error(-ret as i32))
+        } else {
+            Ok(ret as usize)
+        }
+    }
+}
+
+// Lists the names of a file's extended attributes, each ending in a nul, into list. The list is
+// grown when the attributes don't fit.
+pub fn listxattr(
+    dirfd: libc::c_int,
+    name: CStr<'_>,
+    list: &mut Vec<u8>,
+    follow_symlinks: bool,
+) -> Result<(), crate::Error> {
+    let path = proc_path(dirfd, name);
+    loop {
+        list.resize(list.capacity().max(256), 0);
+        let (path, buf, len) = (path.as_ptr(), list.as_mut_ptr(), list.len());
+        let ret = unsafe {
+            if follow_symlinks {
+                sc::syscall!(LISTXATTR, path, buf, len)
+            } else {
+                sc::syscall!(LLISTXATTR, path, buf, len)
+            }
+        } as isize;
+        if ret == -(libc::ERANGE as isize) {
+            let len = list.len();
+            list.reserve(len);
+        } else if ret < 0 {
+            list.clear();
+            return Err(crate::Error(-ret as i32));
+        } else {
+            list.truncate(ret as usize);
+            return Ok(());
+        }
+    }
+}
+
+// A version of fnmatch with FNM_PERIOD, which is how GNU ls matches --ignore and --hide
+// patterns. This supports *, ?, bracket expressions and backslash escapes.
+pub fn fnmatch(pattern: &[u8], name: &[u8]) -> bool {
+    // A leading . in the name can only be matched by a . in the pattern
+    if name.first() == Some(&b'.') && !pattern.starts_with(b".") && !pattern.starts_with(b"\\.") {
+        return false;
+    }
+
+    let (mut p, mut n) = (0, 0);
+    // Where to resume if what follows the last * fails to match
+    let mut backtrack = None;
+    while n < name.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            backtrack = Some((p, n));
+            continue;
+        }
+        if let Some((pattern_len, name_len)) = match_one(&pattern[p..], &name[n..]) {
+            p += pattern_len;
+            n += name_len;
+            continue;
+        }
+        match backtrack {
+            Some((star_p, star_n)) => {
+                p = star_p;
+                n = star_n + next_char(&name[star_n..]).1;
+                backtrack = Some((star_p, n));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|b| *b == b'*')
+}
+
+// Matches the first element of the pattern against the start of the name, returning how many
+// bytes of each were used. Like fnmatch in a UTF-8 locale, this works a character at a time.
+fn match_one(pattern: &[u8], name: &[u8]) -> Option<(usize, usize)> {
+    if name.is_empty() {
+        return None;
+    }
+    let (c, c_len) = next_char(name);
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some((1, c_len)),
+        [b'[', rest @ ..] => match match_bracket(rest, c) {
+            Some((true, len)) => Some((len + 1, c_len)),
+            Some((false, _)) => None,
+            // A [ without a closing ] is just a [
+            None => (c == u32::from(b'[')).then_some((1, 1)),
+        },
+        [b'\\', escaped @ ..] if !escaped.is_empty() => {
+            let (literal, len) = next_char(escaped);
+            (c == literal).then_some((1 + len, c_len))
+        }
+        _ => {
+            let (literal, len) = next_char(pattern);
+            (c == literal).then_some((len, c_len))
+        }
+    }
+}
+
+fn match_bracket(pattern: &[u8], c: u32) -> Option<(bool, usize)> {
+    // Like a UTF-8 locale, letters and spaces outside of ASCII belong to classes too
+    let c_char = char::from_u32(c).unwrap_or_default();
+    let (negated, mut i) = match pattern.first() {
+        Some(b'!' | b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let start = match pattern.get(i)? {
+            b']' if !first => return Some((matched != negated, i + 1)),
+            b'[' if pattern.get(i + 1) == Some(&b':') => {
+                let class = &pattern[i + 2..];
+                let end = class.windows(2).position(|w| w == b":]")?;
+                matched |= match &class[..end] {
+                    b"alnum" => c_char.is_alphanumeric(),
+                    b"alpha" => c_char.is_alphabetic(),
+                    b"blank" => c_char == ' ' || c_char == '\t',
+                    b"cntrl" => c_char.is_control(),
+                    b"digit" => c_char.is_ascii_digit(),
+                    b"graph" => !c_char.is_control() && !c_char.is_whitespace(),
+                    b"lower" => c_char.is_lowercase(),
+                    b"print" => !c_char.is_control(),
+                    b"punct" => {
+                        !c_char.is_control() && !c_char.is_whitespace() && !c_char.is_alphanumeric()
+                    }
+                    b"space" => c_char.is_whitespace(),
+                    b"upper" => c_char.is_uppercase(),
+                    b"xdigit" => c_char.is_ascii_hexdigit(),
+                    _ => false,
+                };
+                i += 2 + end + 2;
+                first = false;
+                continue;
+            }
+            b'\\' => {
+                i += 1;
+                pattern.get(i)?;
+                let (start, len) = next_char(&pattern[i..]);
+                i += len;
+                start
+            }
+            _ => {
+                let (start, len) = next_char(&pattern[i..]);
+                i += len;
+                start
+            }
+        };
+        first = false;
+
+        // A range like a-z, unless the - is right before the ]
+        let end =
+            if pattern.get(i) == Some(&b'-') && !matches!(pattern.get(i + 1), Some(b']') | None) {
+                i += 1;
+                if pattern[i] == b'\\' {
+                    i += 1;
+                }
+                pattern.get(i)?;
+                let (end, len) = next_char(&pattern[i..]);
+                i += len;
+                end
+            } else {
+                start
+            };
+        matched |= start <= c && c <= end;
+    }
+}
+
+// Decodes the UTF-8 character at the start of bytes, which must not be empty. A byte that doesn't
+// start a valid sequence is a character of its own.
+fn next_char(bytes: &[u8]) -> (u32, usize) {
+    let len = match bytes[0] {
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF4 => 4,
+        _ => 1,
+    };
+    match bytes.get(..len).map(core::str::from_utf8) {
+        Some(Ok(s)) if len > 1 => (s.chars().next().unwrap_or_default() as u32, len),
+        _ => (u32::from(bytes[0]), 1),
+    }
+}
@@ -1,7 +1,13 @@
+import calendar
+import fcntl
 import json
 import subprocess
 import difflib
 import os
+import pty
+import struct
+import termios
+import tempfile
 
 p = subprocess.run(['cargo', 'build', '--message-format=json'],
                    stdout=subprocess.PIPE,
@@ -10,6 +16,96 @@ p = subprocess.run(['cargo', 'build', '--message-format=json'],
 messages = [m for m in p.stdout.splitlines() if len(m) > 0]
 exe_location = json.loads(messages[-1])['executable']
 
+
+# Grids are only laid out for a terminal, so these run with stdout on a pty of the given width
+def run_in_terminal(command, width):
+    master, slave = pty.openpty()
+    fcntl.ioctl(slave, termios.TIOCSWINSZ, struct.pack('HHHH', 24, width, 0, 0))
+    attributes = termios.tcgetattr(slave)
+    attributes[1] &= ~termios.OPOST
+    termios.tcsetattr(slave, termios.TCSANOW, attributes)
+    subprocess.run(command, stdout=slave, stderr=subprocess.DEVNULL)
+    os.close(slave)
+    output = b''
+    while True:
+        try:
+            chunk = os.read(master, 4096)
+        except OSError:
+            break
+        if not chunk:
+            break
+        output += chunk
+    os.close(master)
+    return output
+
+
+# What compare_listings creates for each name: a size for a sparse file, bytes for a file's
+# contents, DIRECTORY, FIFO, a Symlink, or a File for a mode or modification time as well
+DIRECTORY = 'directory'
+FIFO = 'fifo'
+
+
+class Symlink:
+    def __init__(self, target):
+        self.target = target
+
+
+class File:
+    def __init__(self, contents=b'', mode=None, mtime=None):
+        self.contents = contents
+        self.mode = mode
+        self.mtime = mtime
+
+
+def make_file(path, spec):
+    if spec == DIRECTORY:
+        os.mkdir(path)
+    elif spec == FIFO:
+        os.mkfifo(path)
+    elif isinstance(spec, Symlink):
+        os.symlink(spec.target, path)
+    elif isinstance(spec, File):
+        make_file(path, spec.contents)
+        if spec.mode is not None:
+            os.chmod(path, spec.mode)
+        if spec.mtime is not None:
+            os.utime(path, (spec.mtime, spec.mtime))
+    else:
+        with open(path, 'wb') as f:
+            if isinstance(spec, bytes):
+                f.write(spec)
+            else:
+                f.truncate(spec)
+
+
+# Creates the files in a scratch directory and lists it with fls and GNU ls for every set of
+# switches. Only the lines keep returns true for are compared, and only what's listed, so files
+# that can't be accessed don't fail the run. With a terminal width, stdout is a pty that wide.
+def compare_listings(files, switch_lists, keep=None, terminal_width=None):
+    with tempfile.TemporaryDirectory() as directory:
+        for name, spec in files.items():
+            make_file(os.path.join(directory, name), spec)
+        for switches in switch_lists:
+            outputs = []
+            for ls in [exe_location, '/bin/ls']:
+                command = [ls] + switches + [directory]
+                if terminal_width is None:
+                    output = subprocess.run(command,
+                                            stdout=subprocess.PIPE,
+                                            stderr=subprocess.DEVNULL).stdout
+                else:
+                    output = run_in_terminal(command, terminal_width)
+                lines = output.split(b'\n')
+                outputs.append([l for l in lines if keep is None or keep(l)])
+            if outputs[0] != outputs[1]:
+                print(' '.join(switches), 'differs:')
+                for (f, g) in zip(*outputs):
+                    if f != g:
+                        print('fls:', f)
+                        print('gnu:', g)
+                        print()
+
+
 sort_orders = ['-c', '-t', '-f', '-rc', '-rt', '-rf']
 
 for switches in sort_orders:
@@ -44,3 +140,116 @@ for switches in long_modes:
                 print('fls:', f)
                 print('gnu:', g)
                 print()
+
+# Files are listed with -f where the order matters, so both list them in the same order
+quoting_names = [
+    'plain', 'with space', "it's", 'dq"x', 'tab\tx', 'nl\nx', 'back\\slash',
+    'dollar$x', 'star*', '\u00e9t\u00e9', '\x01ctl', '-dash', '~tilde', 'a=b',
+    "both'\""
+]
+compare_listings({name: 0 for name in quoting_names}, [
+    ['-f'] + switches for switches in [['-q'], ['-b'], ['-N'], ['-Q']] + [
+        ['--quoting-style=' + style] for style in [
+            'literal', 'shell', 'shell-always', 'shell-escape',
+            'shell-escape-always', 'c', 'escape'
+        ]
+    ]
+])
+
+sort_names = [
+    'a.txt', 'b.tar.gz', 'file10', 'file9', 'file1.2', 'file1.10', 'z', 'ab',
+    'abc.c', 'x.c', 'y.rs', 'noext', 'a1', 'a01', 'v1.2.3', 'v1.10.0', 'c.txt',
+    'zz.c'
+]
+compare_listings({name: len(name) % 4 for name in sort_names}, [
+    switches + reverse
+    for switches in [['-v'], ['--sort=width'], ['--sort=version'],
+                     ['--sort=size'], ['-U']] for reverse in [[], ['-r']]
+])
+
+# Names that tie are ordered by fls's own name sort, so these avoid names where that and byte
+# order disagree
+extension_names = [
+    'a.txt', 'b.tar.gz', 'file.10', 'file.2', 'v.3', 'z', 'ab', 'abc.c', 'x.c',
+    'y.rs', 'noext', 'c.txt', 'zz.c', 'a.C', 'b.c'
+]
+compare_listings({name: 0 for name in extension_names}, [
+    switches + reverse for switches in [['-X'], ['--sort=extension']]
+    for reverse in [[], ['-r']]
+])
+
+# Sizes around where -h and --si round up, carry into another digit or move to the next unit
+sizes = [
+    0, 1, 999, 1000, 1001, 1023, 1024, 1025, 1536, 9215, 10188, 10189, 10239,
+    10240, 10241, 999949, 999950, 1000000, 1047552, 1047553, 1048064, 1048575,
+    1048576, 1048577, 5 * 2**20 + 1, 10 * 2**20 - 1, 10**9, 2**30 - 1, 2**30
+]
+size_files = {'s%d' % size: size for size in sizes}
+size_files.update({
+    'd%d' % size: b'x' * size
+    for size in [1, 4097, 9000, 20000, 200000]
+})
+compare_listings(size_files, [
+    ['-f'] + switches
+    for switches in [['-lh'], ['-lsh'], ['--si', '-ls'], ['--block-size=K', '-ls'],
+                     ['-sh'], ['-l', '--block-size=human-readable'],
+                     ['-l', '--block-size=si'], ['-ls', "--block-size='1"],
+                     ['-s', "--block-size='1K"]]
+])
+
+# Without a terminal -m is laid out differently, so only the total lines are compared
+compare_listings(
+    {
+        'a': b'x',
+        'b': b'x' * 5000,
+        'c': b'x' * 70000,
+        'd': b'x' * 1500000,
+        'sparse': 10**9,
+        'sub': DIRECTORY
+    }, [['-s'], ['-sx'], ['-sm'], ['-1s'], ['-l'], ['-la'], ['-sh'],
+        ['-s', '--si'], ['-sk'], ['-s', '--block-size=M'],
+        ['-ls', '--block-size=1']],
+    keep=lambda line: line.startswith(b'total '))
+
+# Noon UTC is the same day in every timezone, so %j doesn't depend on TZ
+day_of_year_dates = [(2024, 6, 1), (1969, 7, 20), (2024, 2, 29), (2024, 12, 31),
+                     (2023, 12, 31), (2000, 3, 1), (2100, 12, 31), (1968, 12, 31)]
+compare_listings(
+    {
+        '%04d-%02d-%02d' % date: File(mtime=calendar.timegm(date + (12, 0, 0)))
+        for date in day_of_year_dates
+    }, [['-fl', '--time-style=+%j']])
+
+# GNU ls pads grid columns with tabs unless -T0 is given, and fls doesn't by default
+grid_files = {'f%02d' % i: b'x' * (i * 937) for i in range(1, 41)}
+for width in [40, 80, 123]:
+    compare_listings(grid_files, [['--color=never', '-T0'] + switches
+                                  for switches in [['-xs'], ['-xi'], ['-Cs'],
+                                                   ['-Ci'], ['-xsi'], ['-Csi']]],
+                     terminal_width=width)
+
+# -w lays out a grid without a terminal, and -T pads it with tabs
+compare_listings(grid_files, [
+    ['-T0'] + switches
+    for switches in [['-x', '-w80'], ['-C', '-w80'], ['-Cs', '-w60'],
+                     ['-x', '-w0'], ['-C', '-w1'], ['-C', '-w80', '-T8'],
+                     ['-x', '-w70', '-T4'], ['-Ci', '--width=90', '--tabsize=3'],
+                     ['--zero'], ['-ls', '--zero'], ['--zero', '-x', '-w80'],
+                     ['-w80']]
+])
+
+# Long listings put a symlink's indicator after its target, and -L lists a broken one with ?s
+compare_listings(
+    {
+        'd': DIRECTORY,
+        'f': File(mode=0o755),
+        'p': FIFO,
+        'ld': Symlink('d'),
+        'lf': Symlink('f'),
+        'lp': Symlink('p'),
+        'lld': Symlink('ld'),
+        'broken': Symlink('nowhere')
+    }, [['-lF'], ['-l', '--indicator-style=file-type'], ['-lp'],
+        ['-l', '--indicator-style=classify'], ['-lL'], ['-lLF'], ['-lLis'],
+        ['-l', '--file-type'], ['--file-type'], ['-lG'], ['-l', '--author'],
+        ['-g', '--author'], ['-lL', '--author']])